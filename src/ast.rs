@@ -1,40 +1,312 @@
+use crate::token::Span;
+use crate::value::Value;
+use regex::Regex;
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub kind: StatementKind,
+    /// Where the statement starts
+    pub span: Span,
+}
+
 #[derive(Debug)]
 #[derive(Clone)]
-pub enum Statement {
+pub enum StatementKind {
     Server {
         protocol: String,
         port: String,
+        /// Text of the `///` comments before the server
+        doc: Option<String>,
+        body: Vec<Statement>,
+    },
+    /// `import "path"` or `import "path" as name`, resolved by the loader
+    Import {
+        path: String,
+        alias: Option<String>,
+    },
+    /// Declarations from a file imported with `as name`. `name` is fully
+    /// qualified, so modules never nest.
+    Module {
+        name: String,
         body: Vec<Statement>,
     },
     On {
         event: String,
+        doc: Option<String>,
+        body: Vec<Statement>,
+    },
+    Function {
+        name: String,
+        params: Vec<String>,
+        doc: Option<String>,
         body: Vec<Statement>,
     },
+    While {
+        condition: Expression,
+        body: Vec<Statement>,
+    },
+    For {
+        var: String,
+        iterable: Expression,
+        body: Vec<Statement>,
+    },
+    Match {
+        subject: Expression,
+        arms: Vec<MatchArm>,
+    },
+    Break,
+    Continue,
     Log(Expression),
     Send(Expression),
+    Return(Option<Expression>),
+    Expression(Expression),
     SetVar {
         name: String,
         value: Expression,
     },
+    /// File-scope `const`, evaluated once at startup and never reassigned
+    Const {
+        name: String,
+        value: Expression,
+    },
+    SetIndex {
+        name: String,
+        path: Vec<Expression>,
+        value: Expression,
+    },
+    /// `name += value` and friends; `name++` and `name--` update by 1
+    Update {
+        name: String,
+        op: ArithmeticOperator,
+        value: Expression,
+    },
     If {
         condition: Expression,
         then_body: Vec<Statement>,
         else_ifs: Vec<(Expression, Vec<Statement>)>,
         else_body: Option<Vec<Statement>>,
     },
+    /// `try { } catch err { }`; the error message is bound to `catch_var`
+    Try {
+        body: Vec<Statement>,
+        catch_var: Option<String>,
+        catch_body: Vec<Statement>,
+    },
+}
+
+impl StatementKind {
+    pub fn at(self, span: Span) -> Statement {
+        Statement { kind: self, span }
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+/// Renders the statement as a single line of source, eliding nested blocks
+impl fmt::Display for StatementKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatementKind::Server { protocol, port, .. } => write!(f, "server {} {:?} {{ ... }}", protocol, port),
+            StatementKind::Import { path, alias: Some(alias) } => write!(f, "import {:?} as {}", path, alias),
+            StatementKind::Import { path, alias: None } => write!(f, "import {:?}", path),
+            StatementKind::Module { name, .. } => write!(f, "module {}", name),
+            StatementKind::On { event, .. } => write!(f, "on {} {{ ... }}", event),
+            StatementKind::Function { name, params, .. } => {
+                write!(f, "fn {}({}) {{ ... }}", name, params.join(", "))
+            }
+            StatementKind::While { condition, .. } => write!(f, "while {} {{ ... }}", condition),
+            StatementKind::For { var, iterable, .. } => write!(f, "for {} in {} {{ ... }}", var, iterable),
+            StatementKind::Match { subject, .. } => write!(f, "match {} {{ ... }}", subject),
+            StatementKind::Break => write!(f, "break"),
+            StatementKind::Continue => write!(f, "continue"),
+            StatementKind::Log(expr) => write!(f, "log({})", expr),
+            StatementKind::Send(expr) => write!(f, "send({})", expr),
+            StatementKind::Return(Some(expr)) => write!(f, "return {}", expr),
+            StatementKind::Return(None) => write!(f, "return"),
+            StatementKind::Expression(expr) => write!(f, "{}", expr),
+            StatementKind::SetVar { name, value } => write!(f, "set {} = {}", name, value),
+            StatementKind::Const { name, value } => write!(f, "const {} = {}", name, value),
+            StatementKind::SetIndex { name, path, value } => {
+                write!(f, "set {}", name)?;
+                for key in path {
+                    write!(f, "[{}]", key)?;
+                }
+                write!(f, " = {}", value)
+            }
+            StatementKind::Update { name, op, value } => write!(f, "set {} {}= {}", name, op, value),
+            StatementKind::If { condition, .. } => write!(f, "if {} {{ ... }}", condition),
+            StatementKind::Try { .. } => write!(f, "try {{ ... }}"),
+        }
+    }
+}
+
+impl Statement {
+    /// Expressions evaluated by this statement itself, not by its nested blocks
+    pub fn expressions(&self) -> Vec<&Expression> {
+        match &self.kind {
+            StatementKind::While { condition, .. } => vec![condition],
+            StatementKind::For { iterable, .. } => vec![iterable],
+            StatementKind::Match { subject, arms } => {
+                std::iter::once(subject).chain(arms.iter().filter_map(|arm| arm.guard.as_ref())).collect()
+            }
+            StatementKind::Log(expr)
+            | StatementKind::Send(expr)
+            | StatementKind::Return(Some(expr))
+            | StatementKind::Expression(expr)
+            | StatementKind::SetVar { value: expr, .. }
+            | StatementKind::Const { value: expr, .. }
+            | StatementKind::Update { value: expr, .. } => vec![expr],
+            StatementKind::SetIndex { path, value, .. } => path.iter().chain(std::iter::once(value)).collect(),
+            StatementKind::If { condition, else_ifs, .. } => {
+                std::iter::once(condition).chain(else_ifs.iter().map(|(condition, _)| condition)).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Blocks of statements nested directly inside this statement
+    pub fn blocks(&self) -> Vec<&[Statement]> {
+        match &self.kind {
+            StatementKind::Server { body, .. }
+            | StatementKind::Module { body, .. }
+            | StatementKind::On { body, .. }
+            | StatementKind::Function { body, .. }
+            | StatementKind::While { body, .. }
+            | StatementKind::For { body, .. } => vec![body],
+            StatementKind::Match { arms, .. } => arms.iter().map(|arm| arm.body.as_slice()).collect(),
+            StatementKind::If { then_body, else_ifs, else_body, .. } => std::iter::once(then_body)
+                .chain(else_ifs.iter().map(|(_, body)| body))
+                .chain(else_body)
+                .map(Vec::as_slice)
+                .collect(),
+            StatementKind::Try { body, catch_body, .. } => vec![body, catch_body],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// A piece of an interpolated string
+#[derive(Debug, Clone)]
+pub enum TemplatePart {
+    Text(String),
+    Expression(Expression),
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    /// Where the arm's pattern starts
+    pub span: Span,
+    pub guard: Option<Expression>,
+    pub body: Vec<Statement>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `_`
+    Wildcard,
+    /// A bare name that matches anything and binds it
+    Binding(String),
+    /// A string or number compared by equality
+    Literal(Value),
+    /// `"cmd " .. rest` matches strings starting with the prefix, optionally binding the remainder
+    Prefix {
+        prefix: String,
+        rest: Option<String>,
+    },
+    /// `"QUIT" | "EXIT"`
+    Alternatives(Vec<Pattern>),
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Wildcard => write!(f, "_"),
+            Pattern::Binding(name) => write!(f, "{}", name),
+            Pattern::Literal(Value::String(s)) => write!(f, "{:?}", s),
+            Pattern::Literal(value) => write!(f, "{}", value),
+            Pattern::Prefix { prefix, rest: Some(rest) } => write!(f, "{:?} .. {}", prefix, rest),
+            Pattern::Prefix { prefix, rest: None } => write!(f, "{:?} ..", prefix),
+            Pattern::Alternatives(alternatives) => {
+                for (i, alternative) in alternatives.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " | ")?;
+                    }
+                    write!(f, "{}", alternative)?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
-pub enum Expression {
+pub struct Expression {
+    pub kind: ExpressionKind,
+    /// Where the expression starts; for operators, method calls, fields and
+    /// subscripts, where the operator, name or `[` is
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExpressionKind {
     String(String),
+    /// String literal with interpolations, evaluated by `template::eval_template`
+    Template(Vec<TemplatePart>),
     Variable(String),
-    Number(i64),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Null,
+    Regex(Regex),
     MethodCall {
         object: Box<Expression>,
         method: String,
         arg: Option<Box<Expression>>
     },
+    Call {
+        name: String,
+        args: Vec<Expression>,
+    },
+    /// `is_set(name)`: whether the variable has been assigned, without reading it
+    IsSet(String),
+    /// `value ?? default`: `default` when `value` is null or unset
+    Default {
+        value: Box<Expression>,
+        default: Box<Expression>,
+    },
+    /// `condition ? then_value : else_value`; only the chosen branch is evaluated
+    Conditional {
+        condition: Box<Expression>,
+        then_value: Box<Expression>,
+        else_value: Box<Expression>,
+    },
     Tuple(Vec<Expression>),
+    List(Vec<Expression>),
+    Map(Vec<(Expression, Expression)>),
+    Range {
+        start: Box<Expression>,
+        end: Box<Expression>,
+    },
+    Index {
+        object: Box<Expression>,
+        index: Box<Expression>,
+    },
+    /// `object[start..end]`, where either bound may be left out
+    Slice {
+        object: Box<Expression>,
+        start: Option<Box<Expression>>,
+        end: Option<Box<Expression>>,
+    },
+    Field {
+        object: Box<Expression>,
+        name: String,
+    },
     BinaryOp {
         left: Box<Expression>,
         op: BinaryOperator,
@@ -49,6 +321,7 @@ pub enum Expression {
         op: UnaryOperator,
         operand: Box<Expression>,
     },
+    /// `left ~ right`, joining the text of both sides
     Concat {
         left: Box<Expression>,
         right: Box<Expression>,
@@ -79,6 +352,9 @@ pub enum LogicalOperator {
 #[derive(Debug, Clone, PartialEq)]
 pub enum UnaryOperator {
     Not,
+    Negate,
+    /// `+x`, which keeps a number as it is
+    Plus,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -88,4 +364,292 @@ pub enum ArithmeticOperator {
     Multiply,
     Divide,
     Modulo,
-}
\ No newline at end of file
+}
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BinaryOperator::Equal => "==",
+            BinaryOperator::NotEqual => "!=",
+            BinaryOperator::GreaterThan => ">",
+            BinaryOperator::LessThan => "<",
+            BinaryOperator::GreaterEqual => ">=",
+            BinaryOperator::LessEqual => "<=",
+        })
+    }
+}
+
+impl fmt::Display for LogicalOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogicalOperator::And => "&&",
+            LogicalOperator::Or => "||",
+        })
+    }
+}
+
+impl fmt::Display for UnaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UnaryOperator::Not => "!",
+            UnaryOperator::Negate => "-",
+            UnaryOperator::Plus => "+",
+        })
+    }
+}
+
+impl fmt::Display for ArithmeticOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ArithmeticOperator::Add => "+",
+            ArithmeticOperator::Subtract => "-",
+            ArithmeticOperator::Multiply => "*",
+            ArithmeticOperator::Divide => "/",
+            ArithmeticOperator::Modulo => "%",
+        })
+    }
+}
+
+impl ExpressionKind {
+    pub fn at(self, span: Span) -> Expression {
+        Expression { kind: self, span }
+    }
+}
+
+impl Expression {
+    /// Visit this expression and every expression nested inside it, outermost first
+    pub fn walk<'a>(&'a self, visit: &mut dyn FnMut(&'a Expression)) {
+        visit(self);
+        match &self.kind {
+            ExpressionKind::Template(parts) => {
+                for part in parts {
+                    if let TemplatePart::Expression(expr) = part {
+                        expr.walk(visit);
+                    }
+                }
+            }
+            ExpressionKind::MethodCall { object, arg, .. } => {
+                object.walk(visit);
+                if let Some(arg) = arg {
+                    arg.walk(visit);
+                }
+            }
+            ExpressionKind::Call { args: items, .. } | ExpressionKind::Tuple(items) | ExpressionKind::List(items) => {
+                for item in items {
+                    item.walk(visit);
+                }
+            }
+            ExpressionKind::Map(entries) => {
+                for (key, value) in entries {
+                    key.walk(visit);
+                    value.walk(visit);
+                }
+            }
+            ExpressionKind::Conditional { condition, then_value, else_value } => {
+                condition.walk(visit);
+                then_value.walk(visit);
+                else_value.walk(visit);
+            }
+            ExpressionKind::Slice { object, start, end } => {
+                object.walk(visit);
+                for bound in [start, end].into_iter().flatten() {
+                    bound.walk(visit);
+                }
+            }
+            ExpressionKind::Default { value: left, default: right }
+            | ExpressionKind::Range { start: left, end: right }
+            | ExpressionKind::Index { object: left, index: right }
+            | ExpressionKind::BinaryOp { left, right, .. }
+            | ExpressionKind::LogicalOp { left, right, .. }
+            | ExpressionKind::Concat { left, right }
+            | ExpressionKind::Arithmetic { left, right, .. } => {
+                left.walk(visit);
+                right.walk(visit);
+            }
+            ExpressionKind::Field { object: operand, .. } | ExpressionKind::UnaryOp { operand, .. } => operand.walk(visit),
+            ExpressionKind::String(_)
+            | ExpressionKind::Variable(_)
+            | ExpressionKind::Integer(_)
+            | ExpressionKind::Float(_)
+            | ExpressionKind::Boolean(_)
+            | ExpressionKind::Null
+            | ExpressionKind::Regex(_)
+            | ExpressionKind::IsSet(_) => {}
+        }
+    }
+
+    /// Operands that are themselves operations are parenthesized so the
+    /// rendered source keeps its meaning
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExpressionKind::BinaryOp { .. }
+            | ExpressionKind::LogicalOp { .. }
+            | ExpressionKind::Concat { .. }
+            | ExpressionKind::Arithmetic { .. }
+            | ExpressionKind::Default { .. }
+            | ExpressionKind::Conditional { .. }
+            | ExpressionKind::Range { .. } => write!(f, "({})", self),
+            other => write!(f, "{}", other),
+        }
+    }
+}
+
+/// Write string literal text with the escapes needed to lex it back
+fn fmt_string_text(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            '$' if chars.peek().is_some_and(|n| n.is_alphabetic() || *n == '_' || *n == '{') => {
+                write!(f, "\\$")?
+            }
+            c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    Ok(())
+}
+
+fn fmt_list(f: &mut fmt::Formatter<'_>, items: &[Expression]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+/// Renders the expression back to source form
+impl fmt::Display for ExpressionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpressionKind::String(s) => {
+                write!(f, "\"")?;
+                fmt_string_text(f, s)?;
+                write!(f, "\"")
+            }
+            ExpressionKind::Template(parts) => {
+                write!(f, "\"")?;
+                for part in parts {
+                    match part {
+                        TemplatePart::Text(text) => fmt_string_text(f, text)?,
+                        TemplatePart::Expression(expr) => write!(f, "${{{}}}", expr)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            ExpressionKind::Variable(v) if matches!(v.as_str(), "message" | "client" | "error") => {
+                write!(f, "${}", v)
+            }
+            ExpressionKind::Variable(v) => write!(f, "{}", v),
+            ExpressionKind::Integer(n) => write!(f, "{}", n),
+            ExpressionKind::Float(n) => write!(f, "{}", Value::Float(*n)),
+            ExpressionKind::Boolean(b) => write!(f, "{}", b),
+            ExpressionKind::Null => write!(f, "null"),
+            ExpressionKind::IsSet(name) => write!(f, "is_set({})", name),
+            ExpressionKind::Default { value, default } => {
+                value.fmt_operand(f)?;
+                write!(f, " ?? ")?;
+                default.fmt_operand(f)
+            }
+            ExpressionKind::Conditional { condition, then_value, else_value } => {
+                condition.fmt_operand(f)?;
+                write!(f, " ? ")?;
+                then_value.fmt_operand(f)?;
+                write!(f, " : ")?;
+                else_value.fmt_operand(f)
+            }
+            ExpressionKind::Regex(regex) => write!(f, "/{}/", regex.as_str()),
+            ExpressionKind::MethodCall { object, method, arg } => {
+                object.fmt_operand(f)?;
+                write!(f, ".{}(", method)?;
+                match arg.as_deref().map(|arg| &arg.kind) {
+                    Some(ExpressionKind::Tuple(args)) => fmt_list(f, args)?,
+                    Some(arg) => write!(f, "{}", arg)?,
+                    None => {}
+                }
+                write!(f, ")")
+            }
+            ExpressionKind::Call { name, args } => {
+                write!(f, "{}(", name)?;
+                fmt_list(f, args)?;
+                write!(f, ")")
+            }
+            ExpressionKind::Tuple(items) => fmt_list(f, items),
+            ExpressionKind::List(items) => {
+                write!(f, "[")?;
+                fmt_list(f, items)?;
+                write!(f, "]")
+            }
+            ExpressionKind::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                write!(f, "}}")
+            }
+            ExpressionKind::Range { start, end } => {
+                start.fmt_operand(f)?;
+                write!(f, "..")?;
+                end.fmt_operand(f)
+            }
+            ExpressionKind::Index { object, index } => {
+                object.fmt_operand(f)?;
+                write!(f, "[{}]", index)
+            }
+            ExpressionKind::Slice { object, start, end } => {
+                object.fmt_operand(f)?;
+                write!(f, "[")?;
+                if let Some(start) = start {
+                    start.fmt_operand(f)?;
+                }
+                write!(f, "..")?;
+                if let Some(end) = end {
+                    end.fmt_operand(f)?;
+                }
+                write!(f, "]")
+            }
+            ExpressionKind::Field { object, name } => {
+                object.fmt_operand(f)?;
+                write!(f, ".{}", name)
+            }
+            ExpressionKind::BinaryOp { left, op, right } => {
+                left.fmt_operand(f)?;
+                write!(f, " {} ", op)?;
+                right.fmt_operand(f)
+            }
+            ExpressionKind::LogicalOp { left, op, right } => {
+                left.fmt_operand(f)?;
+                write!(f, " {} ", op)?;
+                right.fmt_operand(f)
+            }
+            ExpressionKind::UnaryOp { op, operand } => {
+                write!(f, "{}", op)?;
+                operand.fmt_operand(f)
+            }
+            ExpressionKind::Concat { left, right } => {
+                left.fmt_operand(f)?;
+                write!(f, " ~ ")?;
+                right.fmt_operand(f)
+            }
+            ExpressionKind::Arithmetic { left, op, right } => {
+                left.fmt_operand(f)?;
+                write!(f, " {} ", op)?;
+                right.fmt_operand(f)
+            }
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use crate::ast::{Expression, ExpressionKind, MatchArm, Pattern, Statement, StatementKind};
+use crate::diagnostic::Diagnostic;
+use crate::methods;
+use crate::parser::EVENTS;
+use crate::runtime::{compare_values, module_items, RuntimeError};
+use crate::token::Span;
+use crate::value::Value;
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+/// Variables the runtime provides without a `set`
+const BUILTINS: [&str; 3] = ["message", "client", "error"];
+
+/// Find problems in a loaded program without running it, for `vivo check`.
+/// Unknown event names never get this far: the parser rejects them.
+pub fn check(program: &[Statement]) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> =
+        methods::check_program(program).iter().filter_map(RuntimeError::diagnostic).collect();
+    check_ports(program, &mut diagnostics);
+    check_variables(program, &mut diagnostics);
+    check_handlers(program, &mut diagnostics);
+    check_branches(program, &mut diagnostics);
+    check_arms(program, &mut diagnostics);
+
+    diagnostics.sort_by(|a, b| {
+        (&a.span.file, a.span.line, a.span.column).cmp(&(&b.span.file, b.span.line, b.span.column))
+    });
+    diagnostics
+}
+
+fn problem(message: String, span: &Span) -> Diagnostic {
+    Diagnostic { message, span: span.clone(), help: None }
+}
+
+/// Every statement in `statements` and the blocks nested inside them
+fn walk_statements<'p>(statements: &'p [Statement], visit: &mut dyn FnMut(&'p Statement)) {
+    for stmt in statements {
+        visit(stmt);
+        for block in stmt.blocks() {
+            walk_statements(block, visit);
+        }
+    }
+}
+
+/// A second server on a port fails to bind when it starts
+fn check_ports(program: &[Statement], diagnostics: &mut Vec<Diagnostic>) {
+    let mut seen: HashMap<&str, &Span> = HashMap::new();
+    for (_, stmt) in module_items(program) {
+        if let StatementKind::Server { port, .. } = &stmt.kind {
+            let number = port.trim_start_matches(':');
+            match seen.get(number) {
+                Some(first) => diagnostics.push(problem(
+                    format!("port {} is already used by the server at {}", number, first),
+                    &stmt.span,
+                )),
+                None => {
+                    seen.insert(number, &stmt.span);
+                }
+            }
+        }
+    }
+}
+
+/// `$message` is only set while a message is being handled
+fn check_handlers(program: &[Statement], diagnostics: &mut Vec<Diagnostic>) {
+    for (_, stmt) in module_items(program) {
+        let StatementKind::Server { body, .. } = &stmt.kind else {
+            continue;
+        };
+        for handler in body {
+            let StatementKind::On { event, body, .. } = &handler.kind else {
+                continue;
+            };
+            if event != "connect" && event != "disconnect" {
+                continue;
+            }
+            walk_statements(body, &mut |stmt| {
+                for expr in stmt.expressions() {
+                    expr.walk(&mut |expr| {
+                        if matches!(&expr.kind, ExpressionKind::Variable(name) if name == "message") {
+                            diagnostics.push(problem(
+                                format!("`$message` is always empty in `on {}`", event),
+                                &expr.span,
+                            ));
+                        }
+                    });
+                }
+            });
+        }
+    }
+}
+
+/// Names a statement assigns, not counting nested blocks
+fn assigned<'p>(stmt: &'p Statement, names: &mut HashSet<&'p str>) {
+    fn bindings<'p>(pattern: &'p Pattern, names: &mut HashSet<&'p str>) {
+        match pattern {
+            Pattern::Binding(name) | Pattern::Prefix { rest: Some(name), .. } => {
+                names.insert(name);
+            }
+            Pattern::Alternatives(alternatives) => {
+                for alternative in alternatives {
+                    bindings(alternative, names);
+                }
+            }
+            Pattern::Wildcard | Pattern::Literal(_) | Pattern::Prefix { rest: None, .. } => {}
+        }
+    }
+
+    match &stmt.kind {
+        StatementKind::SetVar { name, .. }
+        | StatementKind::Const { name, .. }
+        | StatementKind::SetIndex { name, .. }
+        | StatementKind::Update { name, .. }
+        | StatementKind::For { var: name, .. }
+        | StatementKind::Try { catch_var: Some(name), .. } => {
+            names.insert(name);
+        }
+        StatementKind::Match { arms, .. } => {
+            for arm in arms {
+                bindings(&arm.pattern, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Named groups of every regex literal and pattern string, which matching
+/// binds as variables. Numbered groups are recognised by their names alone.
+fn capture_names(program: &[Statement]) -> Vec<String> {
+    let mut names = Vec::new();
+    walk_statements(program, &mut |stmt| {
+        for expr in stmt.expressions() {
+            expr.walk(&mut |expr| {
+                // Strings can be patterns too, as in `$message.matches("(?<word>\\w+)")`
+                let regex = match &expr.kind {
+                    ExpressionKind::Regex(regex) => regex.clone(),
+                    ExpressionKind::String(s) => match Regex::new(s) {
+                        Ok(regex) => regex,
+                        Err(_) => return,
+                    },
+                    _ => return,
+                };
+                names.extend(regex.capture_names().flatten().map(str::to_string));
+            });
+        }
+    });
+    names
+}
+
+/// Report variables read where no `set` can have run yet. File-scope
+/// declarations run in order before any server starts. A connection's
+/// handlers share its variables and run in the order of `EVENTS`, so
+/// each sees what the ones before it set. Functions may be called from any
+/// handler, so only names set nowhere at all are reported in them.
+fn check_variables(program: &[Statement], diagnostics: &mut Vec<Diagnostic>) {
+    let captures = capture_names(program);
+    let mut known: HashSet<&str> = BUILTINS.into_iter().chain(captures.iter().map(String::as_str)).collect();
+    let mut everywhere = HashSet::new();
+    for (module, stmt) in module_items(program) {
+        known.extend(module.into_iter().flat_map(|name| name.split('.')));
+        // File-scope `set` and `const` are globals, visible everywhere after them
+        if !matches!(stmt.kind, StatementKind::Server { .. } | StatementKind::Function { .. }) {
+            check_reads(std::slice::from_ref(stmt), &mut known, diagnostics);
+        }
+        walk_statements(std::slice::from_ref(stmt), &mut |stmt| assigned(stmt, &mut everywhere));
+    }
+
+    let mut functions = Vec::new();
+    for (_, stmt) in module_items(program) {
+        match &stmt.kind {
+            StatementKind::Server { body, .. } => {
+                let mut defined = known.clone();
+                for event in EVENTS {
+                    for handler in body {
+                        match &handler.kind {
+                            StatementKind::On { event: handled, body, .. } if handled == event => {
+                                check_reads(body, &mut defined, diagnostics);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                functions.extend(body.iter().filter(|item| matches!(item.kind, StatementKind::Function { .. })));
+            }
+            StatementKind::Function { .. } => functions.push(stmt),
+            _ => {}
+        }
+    }
+
+    for function in functions {
+        if let StatementKind::Function { params, body, .. } = &function.kind {
+            let mut defined = &known | &everywhere;
+            defined.extend(params.iter().map(String::as_str));
+            check_reads(body, &mut defined, diagnostics);
+        }
+    }
+}
+
+/// Variables read by `expressions`, leaving out `$name ?? fallback`, which
+/// is written for variables that may be unset
+fn reads<'p>(expressions: &[&'p Expression]) -> Vec<(&'p str, &'p Span)> {
+    let mut reads = Vec::new();
+    for expr in expressions {
+        let mut guarded: Vec<&Expression> = Vec::new();
+        expr.walk(&mut |expr| match &expr.kind {
+            ExpressionKind::Default { value, .. } => guarded.push(value),
+            ExpressionKind::Variable(name) if !guarded.iter().any(|g| std::ptr::eq(*g, expr)) => {
+                reads.push((name.as_str(), &expr.span));
+            }
+            _ => {}
+        });
+    }
+    reads
+}
+
+/// Check reads in `statements` in the order they are written, adding what
+/// each statement sets to `defined`. A name is reported once; after that it
+/// counts as defined.
+fn check_reads<'p>(statements: &'p [Statement], defined: &mut HashSet<&'p str>, diagnostics: &mut Vec<Diagnostic>) {
+    fn report<'p>(reads: Vec<(&'p str, &'p Span)>, defined: &mut HashSet<&'p str>, diagnostics: &mut Vec<Diagnostic>) {
+        for (name, span) in reads {
+            // `$0`, `$1`, ... are the groups of the latest regex match
+            if !defined.contains(name) && !name.chars().all(|c| c.is_ascii_digit()) {
+                diagnostics.push(problem(format!("`${}` is read before it is set", name), span));
+                defined.insert(name);
+            }
+        }
+    }
+
+    for stmt in statements {
+        // Match guards run after their arm's pattern has bound its names
+        let (before, after) = match &stmt.kind {
+            StatementKind::Match { subject, arms } => {
+                (vec![subject], arms.iter().filter_map(|arm| arm.guard.as_ref()).collect())
+            }
+            _ => (stmt.expressions(), Vec::new()),
+        };
+
+        let mut read = reads(&before);
+        if let StatementKind::SetIndex { name, .. } | StatementKind::Update { name, .. } = &stmt.kind {
+            read.push((name, &stmt.span));
+        }
+        report(read, defined, diagnostics);
+        assigned(stmt, defined);
+        report(reads(&after), defined, diagnostics);
+
+        for block in stmt.blocks() {
+            check_reads(block, defined, diagnostics);
+        }
+    }
+}
+
+/// Whether an expression is a literal that is always truthy or always falsy
+fn constant_truth(expr: &Expression) -> Option<bool> {
+    let value = match &expr.kind {
+        ExpressionKind::Boolean(b) => Value::Boolean(*b),
+        ExpressionKind::Integer(n) => Value::Integer(*n),
+        ExpressionKind::Float(n) => Value::Float(*n),
+        ExpressionKind::String(s) => Value::from(s.as_str()),
+        ExpressionKind::Null => Value::Null,
+        _ => return None,
+    };
+    Some(value.is_truthy())
+}
+
+/// `else if` arms that can never run: ones after an always-true condition,
+/// ones whose condition is always false, and ones repeating an earlier condition
+fn check_branches(program: &[Statement], diagnostics: &mut Vec<Diagnostic>) {
+    walk_statements(program, &mut |stmt| {
+        let StatementKind::If { condition, else_ifs, .. } = &stmt.kind else {
+            return;
+        };
+        let mut earlier = vec![condition];
+        for (else_if, _) in else_ifs {
+            let message = if let Some(always) = earlier.iter().find(|c| constant_truth(c) == Some(true)) {
+                Some(format!("this `else if` can never run: `{}` is always true", always))
+            } else if constant_truth(else_if) == Some(false) {
+                Some(format!("this `else if` can never run: `{}` is always false", else_if))
+            } else {
+                let text = else_if.to_string();
+                earlier.iter().find(|c| c.to_string() == text).map(|same| {
+                    format!("this `else if` can never run: `{}` is already tested at {}", text, same.span)
+                })
+            };
+            if let Some(message) = message {
+                diagnostics.push(problem(message, &else_if.span));
+            }
+            earlier.push(else_if);
+        }
+    });
+}
+
+/// Whether a pattern matches every value
+fn is_irrefutable(pattern: &Pattern) -> bool {
+    match pattern {
+        Pattern::Wildcard | Pattern::Binding(_) => true,
+        Pattern::Alternatives(alternatives) => alternatives.iter().any(is_irrefutable),
+        Pattern::Literal(_) | Pattern::Prefix { .. } => false,
+    }
+}
+
+/// Match arms that can never run because earlier unguarded arms already
+/// cover every value they match. The interpreter also warns about these at startup.
+pub fn check_arms(program: &[Statement], diagnostics: &mut Vec<Diagnostic>) {
+    fn covered(pattern: &Pattern, literals: &[&Value], prefixes: &[&str]) -> bool {
+        match pattern {
+            Pattern::Literal(value) => {
+                literals.iter().any(|seen| compare_values(seen, value) == Some(Ordering::Equal))
+                    || matches!(value, Value::String(s) if prefixes.iter().any(|p| s.starts_with(p)))
+            }
+            Pattern::Prefix { prefix, .. } => prefixes.iter().any(|p| prefix.starts_with(p)),
+            Pattern::Alternatives(alternatives) => {
+                alternatives.iter().all(|alternative| covered(alternative, literals, prefixes))
+            }
+            Pattern::Wildcard | Pattern::Binding(_) => false,
+        }
+    }
+
+    fn remember<'p>(pattern: &'p Pattern, literals: &mut Vec<&'p Value>, prefixes: &mut Vec<&'p str>) {
+        match pattern {
+            Pattern::Literal(value) => literals.push(value),
+            Pattern::Prefix { prefix, .. } => prefixes.push(prefix),
+            Pattern::Alternatives(alternatives) => {
+                for alternative in alternatives {
+                    remember(alternative, literals, prefixes);
+                }
+            }
+            Pattern::Wildcard | Pattern::Binding(_) => {}
+        }
+    }
+
+    fn check_match(arms: &[MatchArm], diagnostics: &mut Vec<Diagnostic>) {
+        let mut literals = Vec::new();
+        let mut prefixes = Vec::new();
+        let mut catch_all: Option<&Pattern> = None;
+
+        for arm in arms {
+            if let Some(earlier) = catch_all {
+                let message = format!("match arm `{}` is unreachable after `{}`", arm.pattern, earlier);
+                diagnostics.push(problem(message, &arm.span));
+            } else if covered(&arm.pattern, &literals, &prefixes) {
+                let message = format!("match arm `{}` is unreachable, earlier arms cover it", arm.pattern);
+                diagnostics.push(problem(message, &arm.span));
+            }
+
+            // Guarded arms may decline a value, so they don't cover anything
+            if arm.guard.is_none() {
+                remember(&arm.pattern, &mut literals, &mut prefixes);
+                if catch_all.is_none() && is_irrefutable(&arm.pattern) {
+                    catch_all = Some(&arm.pattern);
+                }
+            }
+        }
+    }
+
+    walk_statements(program, &mut |stmt| {
+        if let StatementKind::Match { arms, .. } = &stmt.kind {
+            check_match(arms, diagnostics);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use crate::{lexer, parser};
+
+    /// Messages `check` reports for `source`, which must parse cleanly
+    fn problems(source: &str) -> Vec<String> {
+        let tokens = lexer::lex("test.vi", source).expect("source should lex");
+        let (program, errors) = parser::parse(tokens);
+        assert!(errors.is_empty(), "source should parse");
+        check(&program).into_iter().map(|diagnostic| diagnostic.message).collect()
+    }
+
+    /// `body` as the handlers of a server
+    fn server(body: &str) -> String {
+        format!("server tcp \":9026\" {{\n{}\n}}\n", body)
+    }
+
+    #[test]
+    fn reports_read_before_set() {
+        let found = problems(&server("on connect { send($name) }"));
+        assert_eq!(found, ["`$name` is read before it is set"]);
+    }
+
+    #[test]
+    fn earlier_handlers_set_variables_for_later_ones() {
+        let found = problems(&server("on message { send($name) }\non connect { set name = \"guest\" }"));
+        assert!(found.is_empty(), "{:?}", found);
+    }
+
+    #[test]
+    fn match_guards_see_their_bindings() {
+        let found = problems(&server("on message { match $message { word if $word != \"\" => send($word) } }"));
+        assert!(found.is_empty(), "{:?}", found);
+    }
+
+    #[test]
+    fn checks_file_scope_declarations_in_order() {
+        let found = problems("const A = B\nconst B = 1\nset x += 1\nset y = 1\nset y += A\n");
+        assert_eq!(found, ["`$B` is read before it is set", "`$x` is read before it is set"]);
+    }
+
+    #[test]
+    fn reports_message_in_connect() {
+        let found = problems(&server("on connect { send($message) }"));
+        assert_eq!(found, ["`$message` is always empty in `on connect`"]);
+    }
+
+    #[test]
+    fn reports_duplicate_port() {
+        let source = format!("{}{}", server("on message { send(\"a\") }"), server("on message { send(\"b\") }"));
+        let found = problems(&source);
+        assert_eq!(found, ["port 9026 is already used by the server at test.vi:1:1"]);
+    }
+
+    #[test]
+    fn reports_unreachable_else_if() {
+        let found = problems(&server(
+            "on message {\n    if true { send(\"a\") } else if $message == \"b\" { send(\"b\") }\n    \
+             if $message == \"c\" { send(\"c\") } else if $message == \"c\" { send(\"d\") }\n}",
+        ));
+        assert_eq!(
+            found,
+            [
+                "this `else if` can never run: `true` is always true",
+                "this `else if` can never run: `$message == \"c\"` is already tested at test.vi:4:17",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unreachable_match_arms() {
+        let found = problems(&server(
+            "on message {\n    match $message {\n        \"a\" | \"b\" => send(1)\n        \"a\" => send(2)\n        \
+             word if $word == \"c\" => send(3)\n        other => send(4)\n        _ => send(5)\n    }\n}",
+        ));
+        assert_eq!(
+            found,
+            ["match arm `\"a\"` is unreachable, earlier arms cover it", "match arm `_` is unreachable after `other`"]
+        );
+    }
+
+    #[test]
+    fn reports_method_arity() {
+        let found = problems(&server("on message { send($message.trim(1)) }"));
+        assert_eq!(found, ["trim: takes no arguments, got 1"]);
+    }
+}
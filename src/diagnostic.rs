@@ -0,0 +1,135 @@
+use crate::token::Span;
+use std::fmt::Write;
+use std::fs;
+
+/// An error reported compiler-style: a header, the source line it is on with
+/// the offending token underlined, and an optional hint
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    /// Render against `source`, the text of `span.file`. The source line is
+    /// left out if it cannot be found.
+    pub fn render(&self, source: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "error: {}", self.message);
+
+        let number = self.span.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let _ = writeln!(out, "{}--> {}", gutter, self.span);
+
+        if let Some(line) = source.lines().nth(self.span.line.saturating_sub(1)) {
+            // Keep tabs so the caret lines up however the terminal renders them
+            let indent: String = line
+                .chars()
+                .take(self.span.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let _ = writeln!(out, "{} |", gutter);
+            let _ = writeln!(out, "{} | {}", number, line);
+            let _ = writeln!(out, "{} | {}{}", gutter, indent, "^".repeat(token_width(line, self.span.column)));
+        }
+
+        if let Some(help) = &self.help {
+            let _ = writeln!(out, "{} = help: {}", gutter, help);
+        }
+        out
+    }
+
+    /// Render against the file the span points into, read back from disk
+    pub fn render_from_file(&self) -> String {
+        self.render(&fs::read_to_string(&*self.span.file).unwrap_or_default())
+    }
+}
+
+/// Operators written with two characters, underlined as one token
+const OPERATORS: [&str; 15] = ["..", "=>", "==", "!=", ">=", "<=", "&&", "||", "??", "+=", "-=", "*=", "/=", "++", "--"];
+
+/// Width of the token starting at `column` of `line`, for the underline
+fn token_width(line: &str, column: usize) -> usize {
+    let rest: Vec<char> = line.chars().skip(column.saturating_sub(1)).collect();
+    let is_word = |c: &char| c.is_alphanumeric() || *c == '_';
+
+    match rest.first() {
+        Some('$') => 1 + rest[1..].iter().take_while(|c| is_word(c)).count(),
+        Some(c) if is_word(c) => rest.iter().take_while(|c| is_word(c)).count(),
+        Some('"') => {
+            // Up to the closing quote, or the end of the line for multi-line strings
+            let mut escaped = false;
+            for (n, c) in rest.iter().enumerate().skip(1) {
+                match c {
+                    '\\' if !escaped => escaped = true,
+                    '"' if !escaped => return n + 1,
+                    _ => escaped = false,
+                }
+            }
+            rest.len()
+        }
+        Some(_) if rest.len() >= 2 && OPERATORS.contains(&rest[..2].iter().collect::<String>().as_str()) => 2,
+        _ => 1,
+    }
+}
+
+/// The candidate closest to a misspelled `name`, if any is close enough to
+/// be what was meant
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance > 0 && *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Edits turning `a` into `b`, counting inserted, removed and replaced
+/// characters and swapped neighbours
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let replace = rows[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut best = replace.min(rows[i - 1][j] + 1).min(rows[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = best;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, suggest};
+
+    #[test]
+    fn counts_swapped_neighbours_as_one_edit() {
+        assert_eq!(edit_distance("tpc", "tcp"), 1);
+        assert_eq!(edit_distance("mesage", "message"), 1);
+        assert_eq!(edit_distance("send", "send"), 0);
+    }
+
+    #[test]
+    fn suggests_the_closest_candidate() {
+        assert_eq!(suggest("uper", ["upper", "lower", "trim"]), Some("upper"));
+        assert_eq!(suggest("conect", ["connect", "message", "disconnect"]), Some("connect"));
+    }
+
+    #[test]
+    fn ignores_distant_and_exact_names() {
+        assert_eq!(suggest("xyz", ["upper", "lower"]), None);
+        assert_eq!(suggest("trim", ["trim"]), None);
+    }
+}
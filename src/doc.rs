@@ -0,0 +1,52 @@
+use crate::ast::{Statement, StatementKind};
+use crate::runtime::module_items;
+use std::fmt::Write;
+
+/// Render the `///` documentation of a program's servers, their event
+/// handlers and its functions as plain text, for `vivo doc`
+pub fn render(program: &[Statement]) -> String {
+    let mut out = String::new();
+    let items = module_items(program);
+
+    for (_, stmt) in &items {
+        if let StatementKind::Server { protocol, port, doc, body } = &stmt.kind {
+            write_entry(&mut out, 0, &format!("server {} {:?}", protocol, port), doc);
+            for item in body {
+                match &item.kind {
+                    StatementKind::On { event, doc, .. } => {
+                        write_entry(&mut out, 1, &format!("on {}", event), doc);
+                    }
+                    StatementKind::Function { name, params, doc, .. } => {
+                        write_entry(&mut out, 1, &signature(None, name, params), doc);
+                    }
+                    _ => {}
+                }
+            }
+            out.push('\n');
+        }
+    }
+
+    for (module, stmt) in &items {
+        if let StatementKind::Function { name, params, doc, .. } = &stmt.kind {
+            write_entry(&mut out, 0, &signature(*module, name, params), doc);
+        }
+    }
+
+    out
+}
+
+/// `fn name(a, b)`, qualified with the module a function was imported as
+fn signature(module: Option<&str>, name: &str, params: &[String]) -> String {
+    match module {
+        Some(module) => format!("fn {}.{}({})", module, name, params.join(", ")),
+        None => format!("fn {}({})", name, params.join(", ")),
+    }
+}
+
+fn write_entry(out: &mut String, depth: usize, heading: &str, doc: &Option<String>) {
+    let indent = "    ".repeat(depth);
+    let _ = writeln!(out, "{}{}", indent, heading);
+    for line in doc.as_deref().unwrap_or("(undocumented)").lines() {
+        let _ = writeln!(out, "{}    {}", indent, line);
+    }
+}
@@ -1,22 +1,72 @@
-use crate::ast::Statement;
+use crate::ast::{Statement, StatementKind};
+use crate::check;
+use crate::methods;
 use crate::runtime;
 
-pub async fn interpret(ast: Vec<Statement>) {
+/// The program could not start; the reasons have already been printed
+#[derive(Debug)]
+pub struct StartupFailed;
+
+pub async fn interpret(ast: Vec<Statement>, strict: bool) -> Result<(), StartupFailed> {
     let mut handles = vec![];
 
-    for stmt in ast {
-        match stmt {
-            Statement::Server { protocol, port, body } => {
-                let handle = tokio::spawn(async move {
-                    runtime::run_server(&protocol, &port, body).await;
-                });
-                handles.push(handle);
+    // Match arms that can never run are worth a warning, not a refusal to start
+    let mut unreachable = Vec::new();
+    check::check_arms(&ast, &mut unreachable);
+    for warning in unreachable {
+        eprintln!("Warning: {}: {}", warning.span, warning.message);
+    }
+
+    // Calls that can only fail are reported before anything runs
+    let errors = methods::check_program(&ast);
+    if !errors.is_empty() {
+        for error in &errors {
+            match error.diagnostic() {
+                Some(diagnostic) => eprint!("{}", diagnostic.render_from_file()),
+                None => eprintln!("Error: {}", error),
+            }
+        }
+        return Err(StartupFailed);
+    }
+
+    // Servers may come from imported modules; everything else is shared by every server
+    let servers: Vec<_> = runtime::module_items(&ast)
+        .into_iter()
+        .filter_map(|(module, stmt)| match &stmt.kind {
+            StatementKind::Server { protocol, port, body, .. } => {
+                Some((module.map(str::to_string), protocol.clone(), port.clone(), body.clone()))
             }
-            _ => {}
+            _ => None,
+        })
+        .collect();
+    let shared: Vec<_> = ast
+        .into_iter()
+        .filter(|stmt| !matches!(stmt.kind, StatementKind::Server { .. }))
+        .collect();
+
+    // File-scope constants and globals are evaluated once, before any server starts
+    let globals = match runtime::Globals::init(&shared, strict).await {
+        Ok(globals) => globals,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            if let Some(statement) = &error.statement {
+                eprintln!("    in: {}", statement);
+            }
+            return Err(StartupFailed);
         }
+    };
+
+    for (module, protocol, port, body) in servers {
+        let shared = shared.clone();
+        let globals = globals.clone();
+        let handle = tokio::spawn(async move {
+            runtime::run_server(&protocol, &port, body, shared, globals, module, strict).await;
+        });
+        handles.push(handle);
     }
 
     for handle in handles {
         let _ = handle.await;
     }
-}
\ No newline at end of file
+    Ok(())
+}
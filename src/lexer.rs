@@ -1,120 +1,743 @@
-use crate::token::Token;
+use crate::diagnostic::Diagnostic;
+use crate::token::{Span, SpannedToken, StringPart, Token};
 use std::fmt;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum LexError {
     UnterminatedString { line: usize, column: usize },
+    UnterminatedRegex { line: usize, column: usize },
+    UnterminatedComment { line: usize, column: usize },
     InvalidEscape { line: usize, column: usize, character: char },
+    InvalidCharacterCode { line: usize, column: usize, text: String },
     UnexpectedCharacter { line: usize, column: usize, character: char },
+    InvalidNumber { line: usize, column: usize, text: String },
 }
 
-impl fmt::Display for LexError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl LexError {
+    /// Line and column the error was found at
+    pub fn position(&self) -> (usize, usize) {
         match self {
-            LexError::UnterminatedString { line, column } => {
-                write!(f, "Unterminated string at line {}, column {}", line, column)
-            }
-            LexError::InvalidEscape { line, column, character } => {
-                write!(
-                    f,
-                    "Invalid escape sequence '\\{}' at line {}, column {}",
-                    character, line, column
-                )
-            }
-            LexError::UnexpectedCharacter { line, column, character } => {
-                write!(
-                    f,
-                    "Unexpected character '{}' at line {}, column {}",
-                    character, line, column
-                )
-            }
+            LexError::UnterminatedString { line, column }
+            | LexError::UnterminatedRegex { line, column }
+            | LexError::UnterminatedComment { line, column }
+            | LexError::InvalidEscape { line, column, .. }
+            | LexError::InvalidCharacterCode { line, column, .. }
+            | LexError::UnexpectedCharacter { line, column, .. }
+            | LexError::InvalidNumber { line, column, .. } => (*line, *column),
+        }
+    }
+
+    /// What went wrong, without where
+    fn message(&self) -> String {
+        match self {
+            LexError::UnterminatedString { .. } => "unterminated string".to_string(),
+            LexError::UnterminatedRegex { .. } => "unterminated regex literal".to_string(),
+            LexError::UnterminatedComment { .. } => "unterminated block comment".to_string(),
+            LexError::InvalidEscape { character, .. } => format!("invalid escape sequence '\\{}'", character),
+            LexError::InvalidCharacterCode { text, .. } => format!("invalid character code '{}'", text),
+            LexError::UnexpectedCharacter { character, .. } => format!("unexpected character '{}'", character),
+            LexError::InvalidNumber { text, .. } => format!("invalid number '{}'", text),
         }
     }
+
+    /// The error as a diagnostic in `file`
+    pub fn diagnostic(&self, file: &str) -> Diagnostic {
+        let (line, column) = self.position();
+        Diagnostic { message: self.message(), span: Span { file: Arc::from(file), line, column }, help: None }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (line, column) = self.position();
+        write!(f, "{} at line {}, column {}", self.message(), line, column)
+    }
 }
 
 impl std::error::Error for LexError {}
 
-pub fn lex(src: &str) -> Result<Vec<Token>, LexError> {
-    let mut tokens = Vec::new();
-    let mut chars = src.chars().peekable();
-    let mut line = 1;
-    let mut column = 1;
-
-    fn read_ident(
-        chars: &mut std::iter::Peekable<impl Iterator<Item = char>>,
-        column: &mut usize,
-    ) -> String {
-        let mut ident = String::new();
-        while let Some(&c) = chars.peek() {
-            if c.is_alphanumeric() || c == '_' {
-                ident.push(c);
-                chars.next();
+/// Whether the previous token ends a value, in which case `/` means division
+/// rather than the start of a regex literal
+fn ends_value(previous: Option<&Token>) -> bool {
+    matches!(
+        previous,
+        Some(
+            Token::Ident(_)
+                | Token::String(_)
+                | Token::Template(_)
+                | Token::Integer(_)
+                | Token::Float(_)
+                | Token::True
+                | Token::False
+                | Token::Null
+                | Token::Variable(_)
+                | Token::Regex(_)
+                | Token::RParen
+                | Token::RBracket
+        )
+    )
+}
+
+/// Read a numeric literal whose first digit has been consumed: decimal
+/// integers, floats such as `3.14` and `1e3`, and `0x`/`0b`/`0o` integers.
+/// Underscores may separate digits. On failure the literal's text is returned.
+fn read_number(
+    first: char,
+    chars: &mut std::iter::Peekable<impl Iterator<Item = char> + Clone>,
+    column: &mut usize,
+) -> Result<Token, String> {
+    let mut text = first.to_string();
+
+    let radix = match chars.peek() {
+        Some('x' | 'X') if first == '0' => Some(16),
+        Some('b' | 'B') if first == '0' => Some(2),
+        Some('o' | 'O') if first == '0' => Some(8),
+        _ => None,
+    };
+    if let Some(radix) = radix {
+        text.extend(chars.next());
+        *column += 1;
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            text.push(c);
+            *column += 1;
+        }
+        let digits: String = text[2..].chars().filter(|c| *c != '_').collect();
+        return i64::from_str_radix(&digits, radix).map(Token::Integer).map_err(|_| text);
+    }
+
+    let mut is_float = false;
+    let read_digits = |chars: &mut std::iter::Peekable<_>, text: &mut String, column: &mut usize| {
+        while let Some(c) = chars.next_if(|c: &char| c.is_ascii_digit() || *c == '_') {
+            text.push(c);
+            *column += 1;
+        }
+    };
+    read_digits(chars, &mut text, column);
+
+    // Only a digit after the dot makes a fraction, so `0..n` stays a range
+    // and `5.to_str()` a method call
+    let mut lookahead = chars.clone();
+    if lookahead.next() == Some('.') && lookahead.next().is_some_and(|c| c.is_ascii_digit()) {
+        is_float = true;
+        text.extend(chars.next());
+        *column += 1;
+        read_digits(chars, &mut text, column);
+    }
+
+    let mut lookahead = chars.clone();
+    if matches!(lookahead.next(), Some('e' | 'E')) {
+        let sign = lookahead.next_if(|c| *c == '+' || *c == '-');
+        if lookahead.next().is_some_and(|c| c.is_ascii_digit()) {
+            is_float = true;
+            text.extend(chars.next());
+            *column += 1;
+            if sign.is_some() {
+                text.extend(chars.next());
                 *column += 1;
-            } else {
-                break;
             }
+            read_digits(chars, &mut text, column);
         }
-        ident
     }
 
-    fn read_method_chain(
-        chars: &mut std::iter::Peekable<impl Iterator<Item = char>>,
-        s: &mut String,
-        column: &mut usize,
-    ) {
-        while let Some(&c) = chars.peek() {
-            if c == '.' {
-                s.push(c);
-                chars.next();
+    let digits: String = text.chars().filter(|c| *c != '_').collect();
+    if is_float {
+        digits.parse().map(Token::Float).map_err(|_| text)
+    } else {
+        digits.parse().map(Token::Integer).map_err(|_| text)
+    }
+}
+
+/// Whether the `++` or `--` at `chars` increments or decrements: it must
+/// follow a name and end the statement, so `5--2`, `n--1` and `a++b` are
+/// still binary operators on a negated or positive operand
+fn is_postfix_step(tokens: &[Token], chars: &(impl Iterator<Item = char> + Clone)) -> bool {
+    if !matches!(tokens.last(), Some(Token::Ident(_))) {
+        return false;
+    }
+    let mut rest = chars.clone().skip(1).skip_while(|c| *c == ' ' || *c == '\t').peekable();
+    match rest.next() {
+        None | Some('\n' | '\r' | ';' | '}') => true,
+        // A trailing comment
+        Some('/') => matches!(rest.peek(), Some('/' | '*')),
+        Some(_) => false,
+    }
+}
+
+fn read_ident(
+    chars: &mut std::iter::Peekable<impl Iterator<Item = char>>,
+    column: &mut usize,
+) -> String {
+    let mut ident = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_alphanumeric() || c == '_' {
+            ident.push(c);
+            chars.next();
+            *column += 1;
+        } else {
+            break;
+        }
+    }
+    ident
+}
+
+fn read_method_chain(
+    chars: &mut std::iter::Peekable<impl Iterator<Item = char> + Clone>,
+    s: &mut String,
+    column: &mut usize,
+) {
+    while chars.peek() == Some(&'.') {
+        // Only continue the chain when a name follows, so a sentence ending
+        // in "$name." keeps its full stop
+        let mut lookahead = chars.clone();
+        lookahead.next();
+        if !lookahead.peek().is_some_and(|c| c.is_alphabetic() || *c == '_') {
+            break;
+        }
+
+        s.push('.');
+        chars.next();
+        *column += 1;
+        s.push_str(&read_ident(chars, column));
+
+        if chars.peek() == Some(&'(') {
+            let mut depth = 0;
+            while let Some(m) = chars.next_if(|m| *m != '\n') {
+                s.push(m);
                 *column += 1;
-                while let Some(&m) = chars.peek() {
-                    s.push(m);
+                if m == '(' {
+                    depth += 1;
+                } else if m == ')' {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Finds the `}` closing a `${...}` interpolation. String literals inside it
+/// are skipped, so the brace in `"${$s.replace("}", "!")}"` doesn't count.
+#[derive(Default)]
+struct Interpolation {
+    /// Braces still open, counting the one after `$`
+    depth: usize,
+    quoted: bool,
+    escaped: bool,
+}
+
+impl Interpolation {
+    fn open() -> Self {
+        Interpolation { depth: 1, ..Interpolation::default() }
+    }
+
+    /// Account for the next character, returning whether it closed the interpolation
+    fn closed_by(&mut self, ch: char) -> bool {
+        if self.quoted {
+            match ch {
+                _ if self.escaped => self.escaped = false,
+                '\\' => self.escaped = true,
+                '"' => self.quoted = false,
+                _ => {}
+            }
+            return false;
+        }
+        match ch {
+            '"' => self.quoted = true,
+            '{' => self.depth += 1,
+            '}' => {
+                self.depth -= 1;
+                return self.depth == 0;
+            }
+            _ => {}
+        }
+        false
+    }
+}
+
+/// Read the source text of a string body up to its closing delimiter, which is
+/// consumed. Escape pairs and `${...}` interpolations are kept whole, so a quote
+/// inside them doesn't end the string. Returns `None` if the string never ends.
+fn read_string_body(
+    chars: &mut std::iter::Peekable<impl Iterator<Item = char> + Clone>,
+    line: &mut usize,
+    column: &mut usize,
+    raw: bool,
+    multiline: bool,
+) -> Option<String> {
+    let mut body = String::new();
+    // The `${...}` interpolation being read, if any
+    let mut interpolation = Interpolation::default();
+
+    loop {
+        let ch = chars.next()?;
+        if ch == '\n' {
+            if !multiline {
+                return None;
+            }
+            *line += 1;
+            *column = 1;
+            body.push(ch);
+            continue;
+        }
+        *column += 1;
+
+        match ch {
+            '"' if interpolation.depth == 0 => {
+                if !multiline {
+                    return Some(body);
+                }
+                let mut lookahead = chars.clone();
+                if lookahead.next() == Some('"') && lookahead.next() == Some('"') {
                     chars.next();
+                    chars.next();
+                    *column += 2;
+                    return Some(body);
+                }
+            }
+            '\\' if !raw && interpolation.depth == 0 => {
+                body.push(ch);
+                if let Some(escaped) = chars.next_if(|c| *c != '\n') {
+                    body.push(escaped);
                     *column += 1;
-                    if m == ')' {
-                        break;
+                }
+                continue;
+            }
+            '$' if !raw && interpolation.depth == 0 && chars.peek() == Some(&'{') => {
+                body.push(ch);
+                body.extend(chars.next());
+                *column += 1;
+                interpolation = Interpolation::open();
+                continue;
+            }
+            _ if interpolation.depth > 0 => {
+                interpolation.closed_by(ch);
+            }
+            _ => {}
+        }
+        body.push(ch);
+    }
+}
+
+/// Strip the layout of a multi-line string: the line break right after the
+/// opening `"""`, the line holding the closing `"""`, and the indentation the
+/// remaining lines share. Returns the text along with the source position of
+/// its first character and the indentation removed from each following line.
+fn dedent(body: &str, line: usize, column: usize) -> (String, usize, usize, usize) {
+    let blank = |text: &str| text.chars().all(|c| c == ' ' || c == '\t');
+    let mut lines: Vec<&str> = body.split('\n').collect();
+
+    if lines.len() > 1 && lines.last().is_some_and(|last| blank(last)) {
+        lines.pop();
+    }
+    // Text on the same line as the opening quotes is kept as written
+    let first = if lines.len() > 1 && blank(lines[0]) {
+        lines.remove(0);
+        None
+    } else {
+        Some(lines.remove(0))
+    };
+
+    let indent = lines
+        .iter()
+        .filter(|text| !blank(text))
+        .map(|text| text.chars().take_while(|c| *c == ' ' || *c == '\t').count())
+        .min()
+        .unwrap_or(0);
+
+    let mut text: Vec<String> = first.iter().map(|text| text.to_string()).collect();
+    text.extend(lines.iter().map(|line| line.chars().skip(indent).collect::<String>()));
+
+    match first {
+        Some(_) => (text.join("\n"), line, column, indent),
+        None => (text.join("\n"), line + 1, 1 + indent, indent),
+    }
+}
+
+/// Read the hex digits of a `\x41` or `\u{1F600}` escape, whose backslash and
+/// letter have been consumed, returning the escape's source text on failure
+fn read_char_code(
+    kind: char,
+    chars: &mut std::iter::Peekable<impl Iterator<Item = char>>,
+    column: &mut usize,
+) -> Result<char, String> {
+    let mut text = format!("\\{}", kind);
+    let digits = if kind == 'x' {
+        let digits: String = (0..2).map_while(|_| chars.next_if(char::is_ascii_hexdigit)).collect();
+        text.push_str(&digits);
+        if digits.len() < 2 {
+            return Err(text);
+        }
+        digits
+    } else {
+        if chars.next_if_eq(&'{').is_none() {
+            return Err(text);
+        }
+        text.push('{');
+        let digits: String = std::iter::from_fn(|| chars.next_if(char::is_ascii_hexdigit)).collect();
+        text.push_str(&digits);
+        let closed = chars.next_if_eq(&'}').is_some();
+        if closed {
+            text.push('}');
+        }
+        if !closed || !(1..=6).contains(&digits.len()) {
+            return Err(text);
+        }
+        digits
+    };
+    *column += text.chars().count() - 2;
+
+    u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32).ok_or(text)
+}
+
+/// Resolve escapes in a string body and split it into text and the tokens of
+/// each interpolation.
+/// `line` and `column` locate the body in the source; `indent` is the
+/// indentation stripped from each line after the first.
+fn process_string(
+    file: &Arc<str>,
+    text: &str,
+    mut line: usize,
+    mut column: usize,
+    indent: usize,
+) -> Result<Vec<StringPart>, LexError> {
+    let mut chars = text.chars().peekable();
+    let mut parts = Vec::new();
+    let mut s = String::new();
+
+    while let Some(ch) = chars.next() {
+        let ch_column = column;
+        column += 1;
+        match ch {
+            '\n' => {
+                line += 1;
+                column = 1 + indent;
+                s.push(ch);
+            }
+            '\\' => {
+                column += 1;
+                s.push(match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('\\') => '\\',
+                    Some('"') => '"',
+                    Some('$') => '$',
+                    Some('{') => '{',
+                    Some(kind @ ('x' | 'u')) => read_char_code(kind, &mut chars, &mut column)
+                        .map_err(|text| LexError::InvalidCharacterCode { line, column: ch_column, text })?,
+                    other => {
+                        return Err(LexError::InvalidEscape {
+                            line,
+                            column: ch_column,
+                            character: other.unwrap_or('\n'),
+                        });
+                    }
+                });
+            }
+
+            // Interpolation of `${expr}` or `$ident.method()`; a `$` not
+            // followed by a name stays literal text, so `$5.00` is a price.
+            // Numbered regex groups are written `${$1}`.
+            '$' if chars.peek().is_some_and(|n| n.is_alphabetic() || *n == '_' || *n == '{') => {
+                let (source_line, source_column, source) = if chars.peek() == Some(&'{') {
+                    chars.next(); // consume '{'
+                    column += 1;
+                    let (source_line, source_column) = (line, column);
+                    let mut source = String::new();
+                    let mut interpolation = Interpolation::open();
+                    for next_ch in chars.by_ref() {
+                        column += 1;
+                        if next_ch == '\n' {
+                            line += 1;
+                            column = 1 + indent;
+                        }
+                        if interpolation.closed_by(next_ch) {
+                            break;
+                        }
+                        source.push(next_ch);
                     }
+                    (source_line, source_column, source)
+                } else {
+                    let mut source = String::from("$");
+                    source.push_str(&read_ident(&mut chars, &mut column));
+                    read_method_chain(&mut chars, &mut source, &mut column);
+                    (line, ch_column, source)
+                };
+
+                if !s.is_empty() {
+                    parts.push(StringPart::Text(std::mem::take(&mut s)));
                 }
-            } else {
-                break;
+                parts.push(StringPart::Interpolation(lex_from(file, &source, source_line, source_column)?));
             }
+
+            _ => s.push(ch),
         }
     }
 
+    if !s.is_empty() {
+        parts.push(StringPart::Text(s));
+    }
+    Ok(parts)
+}
+
+/// Read a string literal whose opening `"` has just been consumed. A `"""`
+/// opens a multi-line string, whose layout is stripped by `dedent`; raw
+/// strings take backslashes and `$` literally. Strings with interpolations
+/// become `Token::Template`.
+fn read_string(
+    file: &Arc<str>,
+    chars: &mut std::iter::Peekable<impl Iterator<Item = char> + Clone>,
+    line: &mut usize,
+    column: &mut usize,
+    raw: bool,
+) -> Result<Token, LexError> {
+    let unterminated = LexError::UnterminatedString { line: *line, column: *column };
+    *column += 1;
+
+    let mut lookahead = chars.clone();
+    let multiline = lookahead.next() == Some('"') && lookahead.next() == Some('"');
+    if multiline {
+        chars.next();
+        chars.next();
+        *column += 2;
+    }
+
+    let (body_line, body_column) = (*line, *column);
+    let body = read_string_body(chars, line, column, raw, multiline).ok_or(unterminated)?;
+    let (text, text_line, text_column, indent) = if multiline {
+        dedent(&body, body_line, body_column)
+    } else {
+        (body, body_line, body_column, 0)
+    };
+
+    if raw {
+        return Ok(Token::String(text));
+    }
+    let mut parts = process_string(file, &text, text_line, text_column, indent)?;
+    Ok(match parts.as_mut_slice() {
+        [] => Token::String(String::new()),
+        [StringPart::Text(text)] => Token::String(std::mem::take(text)),
+        _ => Token::Template(parts),
+    })
+}
+
+/// Lex the source of `file`, tagging each token with where it starts
+pub fn lex(file: &str, src: &str) -> Result<Vec<SpannedToken>, LexError> {
+    lex_from(&Arc::from(file), src, 1, 1)
+}
+
+/// Lex `src` as if it started at `line` and `column`, so the positions of
+/// tokens and errors in string interpolations point into the enclosing file
+fn lex_from(file: &Arc<str>, src: &str, mut line: usize, mut column: usize) -> Result<Vec<SpannedToken>, LexError> {
+    let mut tokens = Vec::new();
+    let mut spans = Vec::new();
+    let mut chars = src.chars().peekable();
+
     while let Some(c) = chars.next() {
+        // Every token pushed while handling `c` starts where `c` does
+        let start = Span { file: file.clone(), line, column };
         match c {
             '/' => {
                 if let Some(&'/') = chars.peek() {
                     // Line comment
                     chars.next();
-                    while let Some(ch) = chars.peek() {
-                        if *ch == '\n' {
-                            break;
+                    let mut text = String::new();
+                    while let Some(ch) = chars.next_if(|ch| *ch != '\n') {
+                        text.push(ch);
+                        column += 1;
+                    }
+
+                    // `///` starts a doc comment, while `////` is a plain comment
+                    if let Some(doc) = text.strip_prefix('/').filter(|doc| !doc.starts_with('/')) {
+                        let doc = doc.strip_prefix(' ').unwrap_or(doc).trim_end();
+                        tokens.push(Token::DocComment(doc.to_string()));
+                    }
+                } else if let Some(&'*') = chars.peek() {
+                    // Block comment, which may contain nested `/* */` pairs
+                    let comment_start_line = line;
+                    let comment_start_column = column;
+                    chars.next();
+                    column += 2;
+
+                    let mut depth = 1;
+                    while depth > 0 {
+                        match chars.next() {
+                            None => {
+                                return Err(LexError::UnterminatedComment {
+                                    line: comment_start_line,
+                                    column: comment_start_column,
+                                });
+                            }
+                            Some('\n') => {
+                                line += 1;
+                                column = 1;
+                            }
+                            Some('*') if chars.next_if_eq(&'/').is_some() => {
+                                depth -= 1;
+                                column += 2;
+                            }
+                            Some('/') if chars.next_if_eq(&'*').is_some() => {
+                                depth += 1;
+                                column += 2;
+                            }
+                            Some(_) => column += 1,
+                        }
+                    }
+                } else if !ends_value(tokens.last()) {
+                    // Regex literal: /pattern/flags
+                    let regex_start_column = column;
+                    column += 1;
+                    let mut pattern = String::new();
+                    let mut terminated = false;
+
+                    while let Some(ch) = chars.next() {
+                        column += 1;
+                        match ch {
+                            '/' => {
+                                terminated = true;
+                                break;
+                            }
+                            '\n' => break,
+                            '\\' => match chars.next_if(|n| *n != '\n') {
+                                // `\/` only escapes the delimiter; other escapes belong to the regex
+                                Some('/') => {
+                                    column += 1;
+                                    pattern.push('/');
+                                }
+                                Some(n) => {
+                                    column += 1;
+                                    pattern.push('\\');
+                                    pattern.push(n);
+                                }
+                                None => break,
+                            },
+                            _ => pattern.push(ch),
                         }
-                        chars.next();
+                    }
+
+                    if !terminated {
+                        return Err(LexError::UnterminatedRegex { line, column: regex_start_column });
+                    }
+
+                    let mut flags = String::new();
+                    while let Some(flag) = chars.next_if(|f| matches!(f, 'i' | 'm' | 's' | 'x')) {
+                        flags.push(flag);
                         column += 1;
                     }
+                    if !flags.is_empty() {
+                        pattern = format!("(?{}){}", flags, pattern);
+                    }
+
+                    tokens.push(Token::Regex(pattern));
+                } else if let Some(&'=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::SlashEquals);
+                    column += 2;
                 } else {
                     tokens.push(Token::Slash);
                     column += 1;
                 }
             }
-            '{' => tokens.push(Token::LBrace),
-            '}' => tokens.push(Token::RBrace),
-            '(' => tokens.push(Token::LParen),
-            ')' => tokens.push(Token::RParen),
-            ':' => tokens.push(Token::Colon),
-            '.' => tokens.push(Token::Dot),
-            ',' => tokens.push(Token::Comma),
-            '+' => tokens.push(Token::Plus),
-            '-' => tokens.push(Token::Minus),
-            '*' => tokens.push(Token::Star),
-            '%' => tokens.push(Token::Percent),
+            '{' => {
+                tokens.push(Token::LBrace);
+                column += 1;
+            }
+            '}' => {
+                tokens.push(Token::RBrace);
+                column += 1;
+            }
+            '(' => {
+                tokens.push(Token::LParen);
+                column += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                column += 1;
+            }
+            '[' => {
+                tokens.push(Token::LBracket);
+                column += 1;
+            }
+            ']' => {
+                tokens.push(Token::RBracket);
+                column += 1;
+            }
+            ':' => {
+                tokens.push(Token::Colon);
+                column += 1;
+            }
+            '.' => {
+                if let Some(&'.') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::DotDot);
+                    column += 2;
+                } else {
+                    tokens.push(Token::Dot);
+                    column += 1;
+                }
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                column += 1;
+            }
+            '+' => {
+                if let Some(&'=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::PlusEquals);
+                    column += 2;
+                } else if chars.peek() == Some(&'+') && is_postfix_step(&tokens, &chars) {
+                    chars.next();
+                    tokens.push(Token::PlusPlus);
+                    column += 2;
+                } else {
+                    tokens.push(Token::Plus);
+                    column += 1;
+                }
+            }
+            '-' => {
+                if let Some(&'=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::MinusEquals);
+                    column += 2;
+                } else if chars.peek() == Some(&'-') && is_postfix_step(&tokens, &chars) {
+                    chars.next();
+                    tokens.push(Token::MinusMinus);
+                    column += 2;
+                } else {
+                    tokens.push(Token::Minus);
+                    column += 1;
+                }
+            }
+            '*' => {
+                if let Some(&'=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::StarEquals);
+                    column += 2;
+                } else {
+                    tokens.push(Token::Star);
+                    column += 1;
+                }
+            }
+            '%' => {
+                tokens.push(Token::Percent);
+                column += 1;
+            }
+            '~' => {
+                tokens.push(Token::Tilde);
+                column += 1;
+            }
             '=' => {
                 if let Some(&'=') = chars.peek() {
                     chars.next();
                     tokens.push(Token::EqualsEquals);
                     column += 2;
+                } else if let Some(&'>') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::FatArrow);
+                    column += 2;
                 } else {
                     tokens.push(Token::Equals);
                     column += 1;
@@ -135,7 +758,18 @@ pub fn lex(src: &str) -> Result<Vec<Token>, LexError> {
                     tokens.push(Token::Or);
                     column += 2;
                 } else {
-                    return Err(LexError::UnexpectedCharacter { line, column, character: c });
+                    tokens.push(Token::Pipe);
+                    column += 1;
+                }
+            }
+            '?' => {
+                if let Some(&'?') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::QuestionQuestion);
+                    column += 2;
+                } else {
+                    tokens.push(Token::Question);
+                    column += 1;
                 }
             }
             '!' => {
@@ -169,97 +803,14 @@ pub fn lex(src: &str) -> Result<Vec<Token>, LexError> {
                 }
             }
 
-            // String literal
-            '"' => {
-                let string_start_line = line;
-                let string_start_column = column;
-                column += 1;
-                let mut s = String::new();
-                let mut terminated = false;
-
-                while let Some(ch) = chars.next() {
-                    column += 1;
-                    match ch {
-                        '"' => {
-                            terminated = true;
-                            break;
-                        }
-                        '\n' => {
-                            return Err(LexError::UnterminatedString {
-                                line: string_start_line,
-                                column: string_start_column,
-                            });
-                        }
-                        '\\' => {
-                            if let Some(next_ch) = chars.next() {
-                                column += 1;
-                                s.push(match next_ch {
-                                    'n' => '\n',
-                                    't' => '\t',
-                                    'r' => '\r',
-                                    '\\' => '\\',
-                                    '"' => '"',
-                                    other => {
-                                        return Err(LexError::InvalidEscape {
-                                            line,
-                                            column: column - 1,
-                                            character: other,
-                                        });
-                                    }
-                                });
-                            } else {
-                                return Err(LexError::UnterminatedString {
-                                    line: string_start_line,
-                                    column: string_start_column,
-                                });
-                            }
-                        }
-
-                        // Interpolation-like support for `${}` or `$ident.method()`
-                        '$' => {
-                            if chars.peek() == Some(&'{') {
-                                // ${ ... }
-                                s.push_str("{{$");
-                                chars.next(); // consume '{'
-                                column += 1;
-                                s.push('{');
-                                let mut depth = 1;
-                                while let Some(next_ch) = chars.next() {
-                                    column += 1;
-                                    if next_ch == '{' {
-                                        depth += 1;
-                                    } else if next_ch == '}' {
-                                        depth -= 1;
-                                        if depth == 0 {
-                                            s.push('}');
-                                            s.push_str("}");
-                                            break;
-                                        }
-                                    }
-                                    s.push(next_ch);
-                                }
-                            } else {
-                                // $var or $var.method()
-                                let mut var_expr = String::from("$");
-                                var_expr.push_str(&read_ident(&mut chars, &mut column));
-                                read_method_chain(&mut chars, &mut var_expr, &mut column);
-                                s.push_str("{{");
-                                s.push_str(&var_expr);
-                                s.push_str("}}");
-                            }
-                        }
-
-                        _ => s.push(ch),
-                    }
-                }
+            // String literals, including `"""` multi-line strings
+            '"' => tokens.push(read_string(file, &mut chars, &mut line, &mut column, false)?),
 
-                if !terminated {
-                    return Err(LexError::UnterminatedString {
-                        line: string_start_line,
-                        column: string_start_column,
-                    });
-                }
-                tokens.push(Token::String(s));
+            // Raw strings: `r"..."` and `r"""..."""`
+            'r' if chars.peek() == Some(&'"') => {
+                chars.next();
+                column += 1;
+                tokens.push(read_string(file, &mut chars, &mut line, &mut column, true)?);
             }
 
             // Variables outside strings
@@ -270,37 +821,49 @@ pub fn lex(src: &str) -> Result<Vec<Token>, LexError> {
             }
 
             // Identifiers and keywords
-            c if c.is_alphabetic() => {
+            c if c.is_alphabetic() || c == '_' => {
                 let mut ident = String::from(c);
                 column += 1;
                 ident.push_str(&read_ident(&mut chars, &mut column));
                 tokens.push(match ident.as_str() {
                     "server" => Token::Server,
-                    "tcp" => Token::TCP,
+                    "tcp" => Token::Tcp,
                     "on" => Token::On,
                     "log" => Token::Log,
                     "send" => Token::Send,
                     "set" => Token::Set,
+                    "const" => Token::Const,
+                    "import" => Token::Import,
+                    "as" => Token::As,
                     "if" => Token::If,
                     "else" => Token::Else,
+                    "fn" => Token::Fn,
+                    "return" => Token::Return,
+                    "while" => Token::While,
+                    "for" => Token::For,
+                    "in" => Token::In,
+                    "break" => Token::Break,
+                    "continue" => Token::Continue,
+                    "match" => Token::Match,
+                    "try" => Token::Try,
+                    "catch" => Token::Catch,
+                    "true" => Token::True,
+                    "false" => Token::False,
+                    "null" => Token::Null,
                     _ => Token::Ident(ident),
                 });
             }
 
             // Numbers
             c if c.is_ascii_digit() => {
-                let mut num = c.to_string();
+                let number_start_column = column;
                 column += 1;
-                while let Some(&n) = chars.peek() {
-                    if n.is_ascii_digit() {
-                        num.push(n);
-                        chars.next();
-                        column += 1;
-                    } else {
-                        break;
+                match read_number(c, &mut chars, &mut column) {
+                    Ok(token) => tokens.push(token),
+                    Err(text) => {
+                        return Err(LexError::InvalidNumber { line, column: number_start_column, text });
                     }
                 }
-                tokens.push(Token::Number(num));
             }
 
             '\n' => {
@@ -316,8 +879,72 @@ pub fn lex(src: &str) -> Result<Vec<Token>, LexError> {
                 return Err(LexError::UnexpectedCharacter { line, column, character: c });
             }
         }
+        spans.resize(tokens.len(), start);
+    }
+
+    tokens.push(Token::Eof);
+    spans.push(Span { file: file.clone(), line, column });
+    Ok(tokens.into_iter().zip(spans).map(|(token, span)| SpannedToken { token, span }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lex;
+    use crate::token::{StringPart, Token};
+
+    /// Tokens of `source`, without their spans
+    fn tokens(source: &str) -> Vec<Token> {
+        lex("test.vi", source).expect("source should lex").into_iter().map(|t| t.token).collect()
+    }
+
+    /// Parts of the single string literal `source`
+    fn string_parts(source: &str) -> Vec<StringPart> {
+        match tokens(source).as_slice() {
+            [Token::Template(parts), ..] => parts.clone(),
+            [Token::String(text), ..] => vec![StringPart::Text(text.clone())],
+            other => panic!("expected a string, got {:?}", other),
+        }
+    }
+
+    /// Tokens of each interpolation in the string literal `source`
+    fn interpolations(source: &str) -> Vec<Vec<Token>> {
+        string_parts(source)
+            .into_iter()
+            .filter_map(|part| match part {
+                StringPart::Interpolation(tokens) => Some(tokens.into_iter().map(|t| t.token).collect()),
+                StringPart::Text(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn interpolations_skip_braces_in_string_arguments() {
+        let found = interpolations(r#""${$s.replace("}", "!")} and ${$s.replace("{", "<")} ok""#);
+        assert_eq!(found.len(), 2);
+        assert!(found[0].contains(&Token::String("}".to_string())));
+        assert!(found[1].contains(&Token::String("{".to_string())));
+        assert_eq!(string_parts(r#""${$s.replace("}", "!")} ok""#).last(), Some(&StringPart::Text(" ok".to_string())));
+    }
+
+    #[test]
+    fn dollar_before_a_digit_stays_literal() {
+        assert_eq!(tokens(r#""price $5.00""#)[0], Token::String("price $5.00".to_string()));
+        assert_eq!(
+            interpolations(r#""user ${$1} at $host""#),
+            [
+                [Token::Variable("1".to_string()), Token::Eof],
+                [Token::Variable("host".to_string()), Token::Eof],
+            ]
+        );
     }
 
-    tokens.push(Token::EOF);
-    Ok(tokens)
+    #[test]
+    fn steps_only_end_statements() {
+        let name = |n: &str| Token::Ident(n.to_string());
+        assert_eq!(tokens("n--1"), [name("n"), Token::Minus, Token::Minus, Token::Integer(1), Token::Eof]);
+        assert_eq!(tokens("a++b"), [name("a"), Token::Plus, Token::Plus, name("b"), Token::Eof]);
+        assert_eq!(tokens("5--2"), [Token::Integer(5), Token::Minus, Token::Minus, Token::Integer(2), Token::Eof]);
+        assert_eq!(tokens("n++ // bump\nb--;"), [name("n"), Token::PlusPlus, name("b"), Token::MinusMinus, Token::Eof]);
+        assert_eq!(tokens("{ n++ }"), [Token::LBrace, name("n"), Token::PlusPlus, Token::RBrace, Token::Eof]);
+    }
 }
@@ -0,0 +1,186 @@
+use crate::ast::{Statement, StatementKind};
+use crate::lexer::{self, LexError};
+use crate::parser::{self, ParseError};
+use crate::token::Span;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum LoadError {
+    UnsupportedFile { path: PathBuf },
+    Read { path: PathBuf, error: std::io::Error },
+    /// Lex and parse errors keep the file's source for rendering diagnostics
+    Lex { path: PathBuf, source: String, error: LexError },
+    /// Each file the parser recovered from errors in, with its source and
+    /// the errors in the order they were found. `program` is everything
+    /// parsed around them, so `vivo check` can still look at it.
+    Parse { files: Vec<(String, Vec<ParseError>)>, program: Vec<Statement> },
+    ImportCycle { chain: Vec<PathBuf> },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::UnsupportedFile { path } => {
+                write!(f, "{}: only .vi files are supported", path.display())
+            }
+            LoadError::Read { path, error } => {
+                write!(f, "{}: failed to read file: {}", path.display(), error)
+            }
+            LoadError::Lex { path, error, .. } => {
+                write!(f, "{}: Lexer error: {}", path.display(), error)
+            }
+            // Parse errors already start with the file, line and column
+            LoadError::Parse { files, .. } => {
+                let errors: Vec<String> =
+                    files.iter().flat_map(|(_, errors)| errors).map(ParseError::to_string).collect();
+                write!(f, "{}", errors.join("\n"))
+            }
+            LoadError::ImportCycle { chain } => {
+                let chain: Vec<String> = chain.iter().map(|p| p.display().to_string()).collect();
+                write!(f, "Import cycle: {}", chain.join(" -> "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl LoadError {
+    /// Lex and parse errors rendered with the source lines they point at
+    pub fn diagnostic(&self) -> Option<String> {
+        match self {
+            LoadError::Lex { path, source, error } => {
+                Some(error.diagnostic(&path.display().to_string()).render(source))
+            }
+            LoadError::Parse { files, .. } => {
+                let rendered: Vec<String> = files
+                    .iter()
+                    .flat_map(|(source, errors)| errors.iter().map(|error| error.diagnostic().render(source)))
+                    .collect();
+                Some(rendered.join("\n"))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Resolves `import` statements into a single program
+struct Loader {
+    /// Files already loaded, keyed by canonical path and module name
+    loaded: HashSet<(PathBuf, Option<String>)>,
+    /// Files currently being loaded, as (canonical path, path as written)
+    stack: Vec<(PathBuf, PathBuf)>,
+    /// Source and parse errors of each file that had any
+    failures: Vec<(String, Vec<ParseError>)>,
+}
+
+/// Load `path` and everything it imports. Imported declarations come before
+/// those of the importing file; files imported with `as` become `Module`s.
+/// Parse errors don't stop loading, so all of them are reported together.
+pub fn load(path: &Path) -> Result<Vec<Statement>, LoadError> {
+    let mut loader = Loader { loaded: HashSet::new(), stack: Vec::new(), failures: Vec::new() };
+    let mut program = Vec::new();
+    loader.load_file(path, None, &mut program)?;
+    if !loader.failures.is_empty() {
+        return Err(LoadError::Parse { files: loader.failures, program });
+    }
+    Ok(program)
+}
+
+impl Loader {
+    fn load_file(
+        &mut self,
+        path: &Path,
+        module: Option<&str>,
+        program: &mut Vec<Statement>,
+    ) -> Result<(), LoadError> {
+        if path.extension().and_then(|ext| ext.to_str()) != Some("vi") {
+            return Err(LoadError::UnsupportedFile { path: path.to_path_buf() });
+        }
+
+        let canonical = fs::canonicalize(path)
+            .map_err(|error| LoadError::Read { path: path.to_path_buf(), error })?;
+
+        if let Some(start) = self.stack.iter().position(|(open, _)| *open == canonical) {
+            let mut chain: Vec<PathBuf> = self.stack[start..].iter().map(|(_, p)| p.clone()).collect();
+            chain.push(path.to_path_buf());
+            return Err(LoadError::ImportCycle { chain });
+        }
+
+        // A file imported more than once under the same name is only loaded the first time
+        if !self.loaded.insert((canonical.clone(), module.map(str::to_string))) {
+            return Ok(());
+        }
+
+        let src = fs::read_to_string(path)
+            .map_err(|error| LoadError::Read { path: path.to_path_buf(), error })?;
+        let file = path.display().to_string();
+        let tokens = lexer::lex(&file, &src)
+            .map_err(|error| LoadError::Lex { path: path.to_path_buf(), source: src.clone(), error })?;
+        let (ast, errors) = parser::parse(tokens);
+        if !errors.is_empty() {
+            self.failures.push((src, errors));
+        }
+
+        self.stack.push((canonical, path.to_path_buf()));
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        let mut body = Vec::new();
+        for stmt in ast {
+            if let StatementKind::Import { path: import, alias } = stmt.kind {
+                // Imports resolve relative to the importing file; aliases nest
+                // inside the module doing the importing
+                let name = match (module, alias) {
+                    (Some(outer), Some(alias)) => Some(format!("{}.{}", outer, alias)),
+                    (None, Some(alias)) => Some(alias),
+                    (outer, None) => outer.map(str::to_string),
+                };
+                self.load_file(&dir.join(import), name.as_deref(), program)?;
+            } else {
+                body.push(stmt);
+            }
+        }
+
+        self.stack.pop();
+
+        match module {
+            Some(name) => {
+                let span = Span { file: file.into(), line: 1, column: 1 };
+                program.push(StatementKind::Module { name: name.to_string(), body }.at(span));
+            }
+            None => program.extend(body),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, LoadError};
+    use crate::ast::StatementKind;
+    use std::fs;
+
+    #[test]
+    fn parse_errors_keep_the_partial_program() {
+        let dir = std::env::temp_dir().join(format!("vivo-loader-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.vi"), "fn helper() {\n    retrun 1\n}\n").unwrap();
+        fs::write(
+            dir.join("main.vi"),
+            "import \"lib.vi\" as lib\nserver tcp \":9026\" {\n    on message { sned \"x\" }\n}\n",
+        )
+        .unwrap();
+
+        let result = load(&dir.join("main.vi"));
+        fs::remove_dir_all(&dir).unwrap();
+        let Err(LoadError::Parse { files, program }) = result else {
+            panic!("expected parse errors");
+        };
+        assert_eq!(files.iter().map(|(_, errors)| errors.len()).collect::<Vec<_>>(), [1, 1]);
+        assert!(matches!(program[0].kind, StatementKind::Module { .. }));
+        assert!(matches!(program[1].kind, StatementKind::Server { .. }));
+    }
+}
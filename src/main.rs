@@ -1,53 +1,86 @@
-use std::{env, fs};
+use std::env;
 use std::path::Path;
+use std::process;
 
 mod token;
+mod diagnostic;
 mod lexer;
 mod ast;
 mod parser;
+mod loader;
+mod doc;
+mod check;
 mod interpreter;
 mod runtime;
+mod methods;
 mod template;
+mod value;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 4)]
 async fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().skip(1).collect();
 
-    if args.len() < 2 {
-        eprintln!("Usage: vivo <file.vi>");
-        return;
+    // `vivo doc <file.vi>` prints the script's doc comments instead of running it
+    let document = args.first().is_some_and(|arg| arg == "doc");
+    if document {
+        args.remove(0);
     }
 
-    let path = Path::new(&args[1]);
-
-    if path.extension().and_then(|ext| ext.to_str()) != Some("vi") {
-        eprintln!("Error: only .vi files are supported");
-        return;
+    // `vivo check <file.vi>` reports problems without running the script,
+    // exiting non-zero if there are any
+    let checking = args.first().is_some_and(|arg| arg == "check");
+    if checking {
+        args.remove(0);
     }
 
-    let src = match fs::read_to_string(path) {
-        Ok(src) => src,
-        Err(e) => {
-            eprintln!("Error: failed to read '{}': {}", path.display(), e);
-            return;
-        }
+    // `--strict` stops a handler at its first uncaught error instead of skipping the statement
+    let strict = args.iter().any(|arg| arg == "--strict");
+    let Some(file) = args.iter().find(|arg| !arg.starts_with("--")) else {
+        eprintln!("Usage: vivo [--strict] <file.vi>");
+        eprintln!("       vivo doc <file.vi>");
+        eprintln!("       vivo check <file.vi>");
+        return;
     };
 
-    let tokens = match lexer::lex(&src) {
-        Ok(tokens) => tokens,
+    // Parse errors count as problems too, and `check` still looks at the
+    // program parsed around them
+    let (ast, syntax_errors) = match loader::load(Path::new(file)) {
+        Ok(ast) => (ast, 0),
         Err(e) => {
-            eprintln!("Lexer error: {}", e);
-            return;
+            match e.diagnostic() {
+                Some(diagnostic) => eprint!("{}", diagnostic),
+                None => eprintln!("Error: {}", e),
+            }
+            match e {
+                loader::LoadError::Parse { files, program } if checking => {
+                    eprintln!();
+                    (program, files.iter().map(|(_, errors)| errors.len()).sum())
+                }
+                _ => process::exit(1),
+            }
         }
     };
 
-    let ast = match parser::parse(tokens) {
-        Ok(ast) => ast,
-        Err(e) => {
-            eprintln!("Parse error: {}", e);
+    if checking {
+        let diagnostics = check::check(&ast);
+        let problems = syntax_errors + diagnostics.len();
+        if problems == 0 {
+            println!("{}: no problems found", file);
             return;
         }
-    };
+        for diagnostic in &diagnostics {
+            eprintln!("{}", diagnostic.render_from_file());
+        }
+        eprintln!("{}: {} problem(s) found", file, problems);
+        process::exit(1);
+    }
 
-    interpreter::interpret(ast).await;
-}
\ No newline at end of file
+    if document {
+        print!("{}", doc::render(&ast));
+        return;
+    }
+
+    if interpreter::interpret(ast, strict).await.is_err() {
+        process::exit(1);
+    }
+}
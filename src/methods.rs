@@ -0,0 +1,539 @@
+use crate::ast::{Expression, ExpressionKind, Statement, StatementKind};
+use crate::diagnostic;
+use crate::runtime::{argument_error, compare_values, to_regex, type_error, ErrorKind, EvalResult, RuntimeError};
+use crate::value::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+
+/// Types used in method signatures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Any,
+    String,
+    Integer,
+    Float,
+    Boolean,
+    Null,
+    List,
+    Map,
+    Regex,
+    /// A regex literal or a pattern string
+    Pattern,
+}
+
+impl Type {
+    pub fn of(value: &Value) -> Type {
+        match value {
+            Value::String(_) => Type::String,
+            Value::Integer(_) => Type::Integer,
+            Value::Float(_) => Type::Float,
+            Value::Boolean(_) => Type::Boolean,
+            Value::Null => Type::Null,
+            Value::List(_) => Type::List,
+            Value::Map(_) => Type::Map,
+            Value::Regex(_) => Type::Regex,
+        }
+    }
+
+    fn accepts(self, other: Type) -> bool {
+        match self {
+            Type::Any => true,
+            Type::Pattern => matches!(other, Type::String | Type::Regex | Type::Pattern),
+            _ => self == other,
+        }
+    }
+
+    /// Name as reported by `typeof`
+    fn name(self) -> &'static str {
+        match self {
+            Type::Any => "any value",
+            Type::String => "string",
+            Type::Integer => "integer",
+            Type::Float => "float",
+            Type::Boolean => "boolean",
+            Type::Null => "null",
+            Type::List => "list",
+            Type::Map => "map",
+            Type::Regex | Type::Pattern => "regex",
+        }
+    }
+
+    /// Name with an article, for "must be ..." messages
+    fn described(self) -> &'static str {
+        match self {
+            Type::Any => "any value",
+            Type::String => "a string",
+            Type::Integer => "an integer",
+            Type::Float => "a float",
+            Type::Boolean => "a boolean",
+            Type::Null => "null",
+            Type::List => "a list",
+            Type::Map => "a map",
+            Type::Regex => "a regex",
+            Type::Pattern => "a regex or pattern string",
+        }
+    }
+}
+
+/// How a method runs once its receiver and arguments have been checked.
+/// The variant also fixes the type of receiver the method is defined for.
+pub enum Implementation {
+    Any(fn(&Value, &[Value]) -> EvalResult),
+    String(fn(&str, &[Value]) -> EvalResult),
+    List(fn(&[Value], &[Value]) -> EvalResult),
+    Map(fn(&BTreeMap<String, Value>, &[Value]) -> EvalResult),
+    /// `push` and `pop`, which change the list. Called on a variable, they
+    /// update it; on any other list they change a copy.
+    ListInPlace(fn(&mut Vec<Value>, &[Value]) -> EvalResult),
+    /// `matches` and `captures`, which bind the groups of the match in the handler
+    Captures,
+    /// `replace_re`, whose replacement is evaluated once per match
+    ReplaceRe,
+}
+
+/// Signature and implementation of a built-in method
+pub struct Method {
+    /// The method's name followed by its aliases
+    pub names: &'static [&'static str],
+    pub params: &'static [Type],
+    /// How many trailing parameters may be left out
+    pub optional: usize,
+    pub returns: Type,
+    pub implementation: Implementation,
+}
+
+const fn method(
+    names: &'static [&'static str],
+    params: &'static [Type],
+    returns: Type,
+    implementation: Implementation,
+) -> Method {
+    Method { names, params, optional: 0, returns, implementation }
+}
+
+impl Method {
+    const fn optional(mut self, optional: usize) -> Self {
+        self.optional = optional;
+        self
+    }
+
+    pub fn receiver(&self) -> Type {
+        match self.implementation {
+            Implementation::Any(_) => Type::Any,
+            Implementation::String(_) | Implementation::Captures | Implementation::ReplaceRe => Type::String,
+            Implementation::List(_) | Implementation::ListInPlace(_) => Type::List,
+            Implementation::Map(_) => Type::Map,
+        }
+    }
+
+    fn accepts_count(&self, count: usize) -> bool {
+        (self.params.len() - self.optional..=self.params.len()).contains(&count)
+    }
+
+    pub fn arity_message(&self, count: usize) -> String {
+        let (min, max) = (self.params.len() - self.optional, self.params.len());
+        let plural = |n: usize| if n == 1 { "argument" } else { "arguments" };
+        match (min, max) {
+            (0, 0) => format!("takes no arguments, got {}", count),
+            (min, max) if min == max => format!("requires {} {}, got {}", min, plural(min), count),
+            (0, max) => format!("takes at most {} {}, got {}", max, plural(max), count),
+            (min, max) => format!("takes {} to {} arguments, got {}", min, max, count),
+        }
+    }
+
+    /// The first argument whose type the signature rejects
+    fn mismatch(&self, types: &[Option<Type>]) -> Option<String> {
+        self.params.iter().zip(types).enumerate().find_map(|(position, (param, found))| {
+            let found = (*found)?;
+            (!param.accepts(found)).then(|| {
+                format!("argument {} must be {}, got {}", position + 1, param.described(), found.name())
+            })
+        })
+    }
+
+    /// Check evaluated arguments against the signature
+    pub fn check_args(&self, name: &str, args: &[Value]) -> Result<(), RuntimeError> {
+        if !self.accepts_count(args.len()) {
+            return Err(argument_error(name, self.arity_message(args.len())));
+        }
+        let types: Vec<_> = args.iter().map(|arg| Some(Type::of(arg))).collect();
+        match self.mismatch(&types) {
+            Some(message) => Err(argument_error(name, message)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Every built-in method. Methods on any value come first, so they can't be
+/// shadowed by a type's own methods.
+static METHODS: &[Method] = &[
+    method(&["typeof", "type_of"], &[], Type::String, Implementation::Any(|value, _| {
+        Ok(Value::from(value.type_name()))
+    })),
+    method(&["to_str", "to_string"], &[], Type::String, Implementation::Any(|value, _| {
+        Ok(Value::String(value.to_string()))
+    })),
+    method(&["to_int"], &[], Type::Any, Implementation::Any(to_int)),
+    method(&["to_float"], &[], Type::Any, Implementation::Any(to_float)),
+    // Strings
+    method(&["reverse"], &[], Type::String, Implementation::String(|s, _| {
+        Ok(Value::String(s.chars().rev().collect()))
+    })),
+    method(&["upper"], &[], Type::String, Implementation::String(|s, _| Ok(Value::String(s.to_uppercase())))),
+    method(&["lower"], &[], Type::String, Implementation::String(|s, _| Ok(Value::String(s.to_lowercase())))),
+    method(&["length", "len"], &[], Type::Integer, Implementation::String(|s, _| {
+        Ok(Value::from(s.chars().count()))
+    })),
+    method(&["capitalize", "cap"], &[], Type::String, Implementation::String(|s, _| {
+        let mut chars = s.chars();
+        Ok(Value::String(chars.next().map(|c| c.to_uppercase().chain(chars).collect()).unwrap_or_default()))
+    })),
+    method(&["contains"], &[Type::String], Type::Boolean, Implementation::String(|s, args| {
+        Ok(Value::Boolean(s.contains(args[0].to_string().as_str())))
+    })),
+    method(&["starts_with"], &[Type::String], Type::Boolean, Implementation::String(|s, args| {
+        Ok(Value::Boolean(s.starts_with(args[0].to_string().as_str())))
+    })),
+    method(&["ends_with"], &[Type::String], Type::Boolean, Implementation::String(|s, args| {
+        Ok(Value::Boolean(s.ends_with(args[0].to_string().as_str())))
+    })),
+    // Positions count characters, matching string indexing
+    method(&["find"], &[Type::String], Type::Integer, Implementation::String(|s, args| {
+        Ok(s.find(args[0].to_string().as_str())
+            .map(|byte| Value::from(s[..byte].chars().count()))
+            .unwrap_or(Value::Integer(-1)))
+    })),
+    method(&["trim"], &[], Type::String, Implementation::String(|s, _| Ok(Value::from(s.trim())))),
+    method(&["rtrim"], &[], Type::String, Implementation::String(|s, _| Ok(Value::from(s.trim_end())))),
+    method(&["ltrim"], &[], Type::String, Implementation::String(|s, _| Ok(Value::from(s.trim_start())))),
+    method(&["repeat"], &[Type::Integer], Type::String, Implementation::String(repeat)).optional(1),
+    method(&["repeat_sep"], &[Type::Integer, Type::String], Type::String, Implementation::String(|s, args| {
+        match &args[0] {
+            Value::Integer(times) if *times > 0 => {
+                let separator = args[1].to_string();
+                let times = *times as usize;
+                let length = s.len().saturating_mul(times).saturating_add(separator.len().saturating_mul(times - 1));
+                check_repeat_length("repeat_sep", length)?;
+                Ok(Value::String(vec![s; times].join(&separator)))
+            }
+            _ => Ok(Value::from(s)),
+        }
+    })),
+    method(&["replace"], &[Type::String, Type::String], Type::String, Implementation::String(|s, args| {
+        Ok(Value::String(s.replace(&args[0].to_string(), &args[1].to_string())))
+    })),
+    method(&["remove"], &[Type::String], Type::String, Implementation::String(|s, args| {
+        Ok(Value::String(s.replace(args[0].to_string().as_str(), "")))
+    })),
+    method(&["count"], &[Type::String], Type::Integer, Implementation::String(|s, args| {
+        Ok(Value::from(s.matches(args[0].to_string().as_str()).count()))
+    })),
+    method(&["is_empty"], &[], Type::Boolean, Implementation::String(|s, _| Ok(Value::Boolean(s.is_empty())))),
+    method(&["split"], &[Type::String], Type::List, Implementation::String(split)).optional(1),
+    method(&["matches"], &[Type::Pattern], Type::Boolean, Implementation::Captures),
+    method(&["captures"], &[Type::Pattern], Type::Any, Implementation::Captures),
+    method(&["find_all"], &[Type::Pattern], Type::List, Implementation::String(|s, args| {
+        let regex = to_regex(&args[0])?;
+        Ok(Value::List(regex.find_iter(s).map(|m| Value::from(m.as_str())).collect()))
+    })),
+    method(&["replace_re"], &[Type::Pattern, Type::Any], Type::String, Implementation::ReplaceRe),
+    // Lists are immutable: `push` and `pop` return a new list, so update a
+    // variable with `set xs = xs.push(1)`
+    method(&["length", "len"], &[], Type::Integer, Implementation::List(|items, _| Ok(Value::from(items.len())))),
+    method(&["is_empty"], &[], Type::Boolean, Implementation::List(|items, _| Ok(Value::Boolean(items.is_empty())))),
+    // `push` returns the list it built, so `set xs = xs.push(x)` works too
+    method(&["push"], &[Type::Any], Type::List, Implementation::ListInPlace(|items, args| {
+        items.push(args[0].clone());
+        Ok(Value::List(items.clone()))
+    })),
+    method(&["pop"], &[], Type::Any, Implementation::ListInPlace(|items, _| Ok(items.pop().unwrap_or(Value::Null)))),
+    method(&["join"], &[Type::String], Type::String, Implementation::List(|items, args| {
+        let sep = args.first().map(Value::to_string).unwrap_or_default();
+        Ok(Value::String(items.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(&sep)))
+    }))
+    .optional(1),
+    method(&["contains"], &[Type::Any], Type::Boolean, Implementation::List(|items, args| {
+        Ok(Value::Boolean(items.iter().any(|v| compare_values(v, &args[0]) == Some(Ordering::Equal))))
+    })),
+    method(&["sort"], &[], Type::List, Implementation::List(|items, _| {
+        let mut items = items.to_vec();
+        items.sort_by(|a, b| compare_values(a, b).unwrap_or(Ordering::Equal));
+        Ok(Value::List(items))
+    })),
+    method(&["reverse"], &[], Type::List, Implementation::List(|items, _| {
+        Ok(Value::List(items.iter().rev().cloned().collect()))
+    })),
+    // Maps, which like lists are updated by returning a new map
+    method(&["length", "len"], &[], Type::Integer, Implementation::Map(|entries, _| Ok(Value::from(entries.len())))),
+    method(&["is_empty"], &[], Type::Boolean, Implementation::Map(|entries, _| {
+        Ok(Value::Boolean(entries.is_empty()))
+    })),
+    method(&["keys"], &[], Type::List, Implementation::Map(|entries, _| {
+        Ok(Value::List(entries.keys().map(|k| Value::from(k.as_str())).collect()))
+    })),
+    method(&["values"], &[], Type::List, Implementation::Map(|entries, _| {
+        Ok(Value::List(entries.values().cloned().collect()))
+    })),
+    method(&["has"], &[Type::String], Type::Boolean, Implementation::Map(|entries, args| {
+        Ok(Value::Boolean(entries.contains_key(&args[0].to_string())))
+    })),
+    method(&["remove"], &[Type::String], Type::Map, Implementation::Map(|entries, args| {
+        let mut entries = entries.clone();
+        entries.remove(&args[0].to_string());
+        Ok(Value::Map(entries))
+    })),
+];
+
+fn to_int(value: &Value, _: &[Value]) -> EvalResult {
+    match value {
+        Value::Integer(n) => Ok(Value::Integer(*n)),
+        Value::Float(n) => Ok(Value::Integer(*n as i64)),
+        Value::Boolean(b) => Ok(Value::Integer(*b as i64)),
+        Value::String(s) => s
+            .trim()
+            .parse::<i64>()
+            .map(Value::Integer)
+            .map_err(|_| type_error(format!("cannot convert '{}' to integer", s))),
+        Value::Null => Ok(Value::Null),
+        other @ (Value::List(_) | Value::Map(_) | Value::Regex(_)) => {
+            Err(type_error(format!("cannot convert {} to integer", other.type_name())))
+        }
+    }
+}
+
+fn to_float(value: &Value, _: &[Value]) -> EvalResult {
+    match value {
+        Value::Integer(n) => Ok(Value::Float(*n as f64)),
+        Value::Float(n) => Ok(Value::Float(*n)),
+        Value::Boolean(b) => Ok(Value::Float(*b as i64 as f64)),
+        Value::String(s) => s
+            .trim()
+            .parse::<f64>()
+            .map(Value::Float)
+            .map_err(|_| type_error(format!("cannot convert '{}' to float", s))),
+        Value::Null => Ok(Value::Null),
+        other @ (Value::List(_) | Value::Map(_) | Value::Regex(_)) => {
+            Err(type_error(format!("cannot convert {} to float", other.type_name())))
+        }
+    }
+}
+
+/// Longest string, in bytes, `repeat` and `repeat_sep` may build
+const MAX_REPEAT_LENGTH: usize = 1 << 20;
+
+/// Reject a repetition that would build a string longer than `MAX_REPEAT_LENGTH`
+fn check_repeat_length(name: &str, length: usize) -> Result<(), RuntimeError> {
+    if length > MAX_REPEAT_LENGTH {
+        let message = format!("{}: result would be longer than {} bytes", name, MAX_REPEAT_LENGTH);
+        return Err(ErrorKind::Limit(message).into());
+    }
+    Ok(())
+}
+
+/// `repeat()` doubles the string; `repeat(n)` repeats it `n` times
+fn repeat(s: &str, args: &[Value]) -> EvalResult {
+    match args.first() {
+        None => Ok(Value::String(s.repeat(2))),
+        Some(Value::Integer(n)) if *n >= 0 => {
+            check_repeat_length("repeat", s.len().saturating_mul(*n as usize))?;
+            Ok(Value::String(s.repeat(*n as usize)))
+        }
+        Some(_) => Err(argument_error("repeat", "count must not be negative")),
+    }
+}
+
+/// `split()` splits on whitespace; an empty separator splits into characters
+fn split(s: &str, args: &[Value]) -> EvalResult {
+    let parts = match args.first().map(Value::to_string) {
+        None => s.split_whitespace().map(Value::from).collect(),
+        Some(sep) if sep.is_empty() => s.chars().map(|c| Value::String(c.to_string())).collect(),
+        Some(sep) => s.split(sep.as_str()).map(Value::from).collect(),
+    };
+    Ok(Value::List(parts))
+}
+
+/// Find method `name` for a receiver of type `receiver`
+fn find(receiver: Type, name: &str) -> Option<&'static Method> {
+    METHODS.iter().find(|method| method.names.contains(&name) && method.receiver().accepts(receiver))
+}
+
+/// Find method `name` for `receiver`, failing if its type doesn't define one
+pub fn lookup(receiver: &Value, name: &str) -> Result<&'static Method, RuntimeError> {
+    find(Type::of(receiver), name).ok_or_else(|| unknown_method(receiver, name).into())
+}
+
+/// The method of `receiver`'s type, or of any type if it is unknown, that
+/// `name` looks like a misspelling of
+fn suggest(receiver: Option<Type>, name: &str) -> Option<&'static str> {
+    let names = METHODS
+        .iter()
+        .filter(|method| receiver.is_none_or(|t| method.receiver().accepts(t)))
+        .flat_map(|method| method.names.iter().copied());
+    diagnostic::suggest(name, names)
+}
+
+/// Error for calling `name` on a value whose type has no such method
+pub fn unknown_method(receiver: &Value, name: &str) -> ErrorKind {
+    ErrorKind::UnknownMethod {
+        method: name.to_string(),
+        type_name: receiver.type_name(),
+        suggestion: suggest(Some(Type::of(receiver)), name),
+    }
+}
+
+/// Arguments of a method call; several arguments are parsed as a tuple
+pub fn call_args(arg: Option<&Expression>) -> Vec<&Expression> {
+    match arg {
+        Some(Expression { kind: ExpressionKind::Tuple(args), .. }) => args.iter().collect(),
+        Some(arg) => vec![arg],
+        None => Vec::new(),
+    }
+}
+
+/// Type of an expression when it is known without running the script
+fn static_type(expr: &Expression) -> Option<Type> {
+    match &expr.kind {
+        ExpressionKind::String(_) | ExpressionKind::Template(_) | ExpressionKind::Concat { .. } => Some(Type::String),
+        ExpressionKind::Variable(name) if name == "message" => Some(Type::String),
+        ExpressionKind::Integer(_) => Some(Type::Integer),
+        ExpressionKind::Float(_) => Some(Type::Float),
+        ExpressionKind::Boolean(_) => Some(Type::Boolean),
+        ExpressionKind::Null => Some(Type::Null),
+        ExpressionKind::List(_) => Some(Type::List),
+        ExpressionKind::Map(_) => Some(Type::Map),
+        ExpressionKind::Regex(_) => Some(Type::Regex),
+        ExpressionKind::Slice { object, .. } => static_type(object),
+        ExpressionKind::MethodCall { object, method, .. } => {
+            let returns = find(static_type(object)?, method)?.returns;
+            (returns != Type::Any).then_some(returns)
+        }
+        _ => None,
+    }
+}
+
+/// The variable at the root of `a.b.c`
+fn root_variable(expr: &Expression) -> Option<&str> {
+    match &expr.kind {
+        ExpressionKind::Variable(name) => Some(name),
+        ExpressionKind::Field { object, .. } => root_variable(object),
+        _ => None,
+    }
+}
+
+/// Check a method call against the registry using what is known before the
+/// script runs: the receiver's type when it is evident, and the number and
+/// evident types of the arguments
+fn check_call(
+    object: &Expression,
+    method: &str,
+    arg: Option<&Expression>,
+    modules: &HashSet<&str>,
+) -> Result<(), ErrorKind> {
+    let receiver = static_type(object);
+    // `alias.name(...)` calls a function of a module imported `as alias`
+    if receiver.is_none() && root_variable(object).is_some_and(|name| modules.contains(name)) {
+        return Ok(());
+    }
+
+    let candidates: Vec<&Method> = METHODS
+        .iter()
+        .filter(|m| m.names.contains(&method) && receiver.is_none_or(|t| m.receiver().accepts(t)))
+        .collect();
+    let Some(first) = candidates.first() else {
+        let type_name = receiver.map_or(Type::Any.name(), Type::name);
+        return Err(ErrorKind::UnknownMethod {
+            method: method.to_string(),
+            type_name,
+            suggestion: suggest(receiver, method),
+        });
+    };
+
+    let args = call_args(arg);
+    let argument = |message| ErrorKind::Argument { name: method.to_string(), message };
+    let fitting: Vec<&&Method> = candidates.iter().filter(|m| m.accepts_count(args.len())).collect();
+    if fitting.is_empty() {
+        return Err(argument(first.arity_message(args.len())));
+    }
+
+    let types: Vec<Option<Type>> = args.iter().map(|arg| static_type(arg)).collect();
+    let mismatches: Vec<String> = fitting.iter().filter_map(|m| m.mismatch(&types)).collect();
+    if mismatches.len() == fitting.len() {
+        return Err(argument(mismatches.into_iter().next().unwrap_or_default()));
+    }
+    Ok(())
+}
+
+fn check_statements(statements: &[Statement], modules: &HashSet<&str>, errors: &mut Vec<RuntimeError>) {
+    for stmt in statements {
+        for expr in stmt.expressions() {
+            expr.walk(&mut |expr| {
+                if let ExpressionKind::MethodCall { object, method, arg } = &expr.kind {
+                    if let Err(kind) = check_call(object, method, arg.as_deref(), modules) {
+                        errors.push(RuntimeError {
+                            kind,
+                            statement: Some(stmt.to_string()),
+                            span: Some(expr.span.clone()),
+                        });
+                    }
+                }
+            });
+        }
+        for block in stmt.blocks() {
+            check_statements(block, modules, errors);
+        }
+    }
+}
+
+/// Check every method call in the program against the registry before any
+/// server starts, reporting unknown methods and wrong arguments that are
+/// certain to fail
+pub fn check_program(program: &[Statement]) -> Vec<RuntimeError> {
+    fn module_names<'p>(statements: &'p [Statement], names: &mut HashSet<&'p str>) {
+        for stmt in statements {
+            if let StatementKind::Module { name, body, .. } = &stmt.kind {
+                names.extend(name.split('.'));
+                module_names(body, names);
+            }
+        }
+    }
+
+    let mut modules = HashSet::new();
+    module_names(program, &mut modules);
+
+    let mut errors = Vec::new();
+    check_statements(program, &modules, &mut errors);
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lookup, Implementation};
+    use crate::runtime::{ErrorKind, EvalResult, RuntimeError};
+    use crate::value::Value;
+
+    /// Call the string method `name` on `receiver`
+    fn call(receiver: &str, name: &str, args: &[Value]) -> EvalResult {
+        let method = lookup(&Value::from(receiver), name).expect("method should exist");
+        match method.implementation {
+            Implementation::String(apply) => apply(receiver, args),
+            _ => panic!("`{}` should be a string method", name),
+        }
+    }
+
+    fn is_limit(result: EvalResult) -> bool {
+        matches!(result, Err(RuntimeError { kind: ErrorKind::Limit(_), .. }))
+    }
+
+    #[test]
+    fn repeats_within_the_limit() {
+        assert_eq!(call("ab", "repeat", &[Value::Integer(3)]).unwrap().to_string(), "ababab");
+        assert_eq!(call("ab", "repeat_sep", &[Value::Integer(3), Value::from("-")]).unwrap().to_string(), "ab-ab-ab");
+    }
+
+    #[test]
+    fn huge_repeats_hit_the_limit() {
+        assert!(is_limit(call("ab", "repeat", &[Value::Integer(i64::MAX)])));
+        assert!(is_limit(call("ab", "repeat_sep", &[Value::Integer(i64::MAX), Value::from("-")])));
+        assert!(is_limit(call("ab", "repeat", &[Value::Integer(1 << 20)])));
+    }
+}
@@ -1,104 +1,217 @@
-use crate::token::Token;
-use crate::ast::{Statement, Expression, BinaryOperator, LogicalOperator, UnaryOperator, ArithmeticOperator};
+use crate::token::{Span, SpannedToken, StringPart, Token};
+use crate::ast::{Statement, StatementKind, Expression, ExpressionKind, TemplatePart, BinaryOperator, LogicalOperator, UnaryOperator, ArithmeticOperator, MatchArm, Pattern};
+use crate::value::Value;
+use crate::diagnostic::{self, Diagnostic};
+use regex::Regex;
 use std::fmt;
 
 #[derive(Debug)]
 pub enum ParseError {
-    UnexpectedToken { expected: String, found: String, position: usize },
-    UnexpectedEof { expected: String },
-    InvalidExpression { position: usize },
+    /// `suggestion` is what a misspelled name was probably meant to be
+    UnexpectedToken { expected: String, found: String, span: Span, suggestion: Option<&'static str> },
+    UnexpectedEof { expected: String, span: Span },
+    InvalidRegex { pattern: String, message: String, span: Span },
+}
+
+impl ParseError {
+    pub fn span(&self) -> &Span {
+        match self {
+            ParseError::UnexpectedToken { span, .. }
+            | ParseError::UnexpectedEof { span, .. }
+            | ParseError::InvalidRegex { span, .. } => span,
+        }
+    }
+
+    /// What went wrong, without where
+    fn message(&self) -> String {
+        match self {
+            ParseError::UnexpectedToken { expected, found, .. } => format!("expected {}, found {}", expected, found),
+            ParseError::UnexpectedEof { expected, .. } => format!("unexpected end of file, expected {}", expected),
+            ParseError::InvalidRegex { pattern, message, .. } => format!("invalid regex /{}/: {}", pattern, message),
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        let help = match self {
+            ParseError::UnexpectedToken { suggestion: Some(name), .. } => Some(format!("did you mean `{}`?", name)),
+            _ => None,
+        };
+        Diagnostic { message: self.message(), span: self.span().clone(), help }
+    }
 }
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            ParseError::UnexpectedToken { expected, found, position } => {
-                write!(f, "Parse error at position {}: expected {}, found {}", position, expected, found)
-            }
-            ParseError::UnexpectedEof { expected } => {
-                write!(f, "Unexpected end of file: expected {}", expected)
-            }
-            ParseError::InvalidExpression { position } => {
-                write!(f, "Invalid expression at position {}", position)
-            }
+        write!(f, "{}: {}", self.span(), self.message())?;
+        if let ParseError::UnexpectedToken { suggestion: Some(name), .. } = self {
+            write!(f, "; did you mean `{}`?", name)?;
         }
+        Ok(())
     }
 }
 
 impl std::error::Error for ParseError {}
 
+/// Events a server can handle with `on`
+pub const EVENTS: [&str; 4] = ["connect", "message", "disconnect", "error"];
+
+/// Keywords that can start a statement inside a block
+const STATEMENT_KEYWORDS: [&str; 11] =
+    ["set", "if", "match", "while", "for", "try", "log", "send", "return", "break", "continue"];
+
 type ParseResult<T> = Result<T, ParseError>;
 
-fn parse_expression(tokens: &[Token], i: &mut usize) -> ParseResult<Expression> {
-    parse_concatenation(tokens, i)
+/// The token at `i`, if any
+fn token_at(tokens: &[SpannedToken], i: usize) -> Option<&Token> {
+    tokens.get(i).map(|t| &t.token)
 }
 
-fn parse_concatenation(tokens: &[Token], i: &mut usize) -> ParseResult<Expression> {
-    let mut left = parse_logical_or(tokens, i)?;
+/// The token at `i` as it would be written, for error messages
+fn describe(tokens: &[SpannedToken], i: usize) -> String {
+    match token_at(tokens, i) {
+        None | Some(Token::Eof) => "end of file".to_string(),
+        Some(Token::DocComment(_)) => "doc comment".to_string(),
+        Some(token) => format!("`{}`", token),
+    }
+}
 
-    while *i < tokens.len() && matches!(tokens[*i], Token::Plus) {
-        // Check if this is string concatenation or arithmetic
-        // We need to be context-aware: if we're in a string context, it's concat
-        // For now, we'll handle this by type - if left side is a string, it's concat
-        *i += 1; // skip '+'
-        let right = parse_logical_or(tokens, i)?;
+/// An error for the token at `i`, which is not what was `expected`
+fn unexpected(tokens: &[SpannedToken], i: usize, expected: &str) -> ParseError {
+    ParseError::UnexpectedToken {
+        expected: expected.to_string(),
+        found: describe(tokens, i),
+        span: span_at(tokens, i),
+        suggestion: None,
+    }
+}
 
-        // We'll decide at parse time: if either side could be a string, use Concat
-        // Otherwise use Arithmetic. The runtime will handle conversion.
-        left = Expression::Concat {
-            left: Box::new(left),
-            right: Box::new(right),
-        };
+/// Like `unexpected`, suggesting the closest of `names` when the token at `i`
+/// looks like a misspelling of one
+fn misspelled(tokens: &[SpannedToken], i: usize, expected: &str, names: &[&'static str]) -> ParseError {
+    let suggestion = match token_at(tokens, i) {
+        Some(Token::Ident(name)) => diagnostic::suggest(name, names.iter().copied()),
+        _ => None,
+    };
+    ParseError::UnexpectedToken {
+        expected: expected.to_string(),
+        found: describe(tokens, i),
+        span: span_at(tokens, i),
+        suggestion,
     }
+}
 
-    Ok(left)
+/// Where the token at `i` starts; past the end, where the input ends.
+/// The lexer always ends the tokens with `Eof`, so there is at least one.
+fn span_at(tokens: &[SpannedToken], i: usize) -> Span {
+    tokens[i.min(tokens.len() - 1)].span.clone()
+}
+
+fn parse_expression(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
+    parse_conditional(tokens, i)
+}
+
+/// Parse the tokens of a standalone expression, such as a `${...}` interpolation
+fn parse_expression_tokens(tokens: &[SpannedToken]) -> ParseResult<Expression> {
+    let mut i = 0;
+    let expr = parse_expression(tokens, &mut i)?;
+    match token_at(tokens, i) {
+        None | Some(Token::Eof) => Ok(expr),
+        Some(_) => Err(unexpected(tokens, i, "end of expression")),
+    }
+}
+
+// `cond ? a : b` binds loosest of all and nests to the right
+fn parse_conditional(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
+    let condition = parse_default(tokens, i)?;
+    if !matches!(token_at(tokens, *i), Some(Token::Question)) {
+        return Ok(condition);
+    }
+    let span = span_at(tokens, *i);
+    *i += 1; // skip '?'
+
+    let then_value = parse_expression(tokens, i)?;
+    if !matches!(token_at(tokens, *i), Some(Token::Colon)) {
+        return Err(unexpected(tokens, *i, "':'"));
+    }
+    *i += 1; // skip ':'
+
+    let else_value = parse_conditional(tokens, i)?;
+    Ok(ExpressionKind::Conditional {
+        condition: Box::new(condition),
+        then_value: Box::new(then_value),
+        else_value: Box::new(else_value),
+    }
+    .at(span))
 }
 
-fn parse_logical_or(tokens: &[Token], i: &mut usize) -> ParseResult<Expression> {
+// `??` binds looser than the operators, so `a || b ?? c` defaults the whole condition
+fn parse_default(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
+    let mut value = parse_logical_or(tokens, i)?;
+
+    while *i < tokens.len() && matches!(tokens[*i].token, Token::QuestionQuestion) {
+        let span = span_at(tokens, *i);
+        *i += 1; // skip '??'
+        let default = parse_logical_or(tokens, i)?;
+        value = ExpressionKind::Default {
+            value: Box::new(value),
+            default: Box::new(default),
+        }
+        .at(span);
+    }
+
+    Ok(value)
+}
+
+fn parse_logical_or(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
     let mut left = parse_logical_and(tokens, i)?;
 
-    while *i < tokens.len() && matches!(tokens[*i], Token::Or) {
+    while *i < tokens.len() && matches!(tokens[*i].token, Token::Or) {
+        let span = span_at(tokens, *i);
         *i += 1; // skip '||'
         let right = parse_logical_and(tokens, i)?;
-        left = Expression::LogicalOp {
+        left = ExpressionKind::LogicalOp {
             left: Box::new(left),
             op: LogicalOperator::Or,
             right: Box::new(right),
-        };
+        }
+        .at(span);
     }
 
     Ok(left)
 }
 
-fn parse_logical_and(tokens: &[Token], i: &mut usize) -> ParseResult<Expression> {
+fn parse_logical_and(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
     let mut left = parse_comparison(tokens, i)?;
 
-    while *i < tokens.len() && matches!(tokens[*i], Token::And) {
+    while *i < tokens.len() && matches!(tokens[*i].token, Token::And) {
+        let span = span_at(tokens, *i);
         *i += 1; // skip '&&'
         let right = parse_comparison(tokens, i)?;
-        left = Expression::LogicalOp {
+        left = ExpressionKind::LogicalOp {
             left: Box::new(left),
             op: LogicalOperator::And,
             right: Box::new(right),
-        };
+        }
+        .at(span);
     }
 
     Ok(left)
 }
 
-fn parse_comparison(tokens: &[Token], i: &mut usize) -> ParseResult<Expression> {
+fn parse_comparison(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
     if *i >= tokens.len() {
         return Err(ParseError::UnexpectedEof {
             expected: "expression".to_string(),
+            span: span_at(tokens, *i),
         });
     }
 
     // Parse arithmetic expression first
-    let mut expr = parse_arithmetic(tokens, i)?;
+    let mut expr = parse_range(tokens, i)?;
 
     // Check for comparison operators
     if *i < tokens.len() {
-        let op = match &tokens[*i] {
+        let op = match &tokens[*i].token {
             Token::EqualsEquals => Some(BinaryOperator::Equal),
             Token::NotEquals => Some(BinaryOperator::NotEqual),
             Token::GreaterThan => Some(BinaryOperator::GreaterThan),
@@ -109,269 +222,814 @@ fn parse_comparison(tokens: &[Token], i: &mut usize) -> ParseResult<Expression>
         };
 
         if let Some(operator) = op {
+            let span = span_at(tokens, *i);
             *i += 1; // skip operator
-            let right = parse_arithmetic(tokens, i)?;
-            expr = Expression::BinaryOp {
+            let right = parse_range(tokens, i)?;
+            expr = ExpressionKind::BinaryOp {
                 left: Box::new(expr),
                 op: operator,
                 right: Box::new(right),
-            };
+            }
+            .at(span);
         }
     }
 
     Ok(expr)
 }
 
-fn parse_arithmetic(tokens: &[Token], i: &mut usize) -> ParseResult<Expression> {
+// Parse `start..end`, an exclusive integer range
+fn parse_range(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
+    let start = parse_concatenation(tokens, i)?;
+
+    if *i < tokens.len() && matches!(tokens[*i].token, Token::DotDot) {
+        let span = span_at(tokens, *i);
+        *i += 1; // skip '..'
+        let end = parse_concatenation(tokens, i)?;
+        return Ok(ExpressionKind::Range {
+            start: Box::new(start),
+            end: Box::new(end),
+        }
+        .at(span));
+    }
+
+    Ok(start)
+}
+
+// `~` joins the text of its operands and binds looser than arithmetic,
+// so `"Total: " ~ 1 + 2` is "Total: 3"
+fn parse_concatenation(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
+    let mut left = parse_arithmetic(tokens, i)?;
+
+    while *i < tokens.len() && matches!(tokens[*i].token, Token::Tilde) {
+        let span = span_at(tokens, *i);
+        *i += 1; // skip '~'
+        let right = parse_arithmetic(tokens, i)?;
+        left = ExpressionKind::Concat {
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+        .at(span);
+    }
+
+    Ok(left)
+}
+
+fn parse_arithmetic(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
     parse_additive(tokens, i)
 }
 
-fn parse_additive(tokens: &[Token], i: &mut usize) -> ParseResult<Expression> {
+fn parse_additive(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
     let mut left = parse_multiplicative(tokens, i)?;
 
     while *i < tokens.len() {
-        let op = match &tokens[*i] {
+        let op = match &tokens[*i].token {
             Token::Plus => ArithmeticOperator::Add,
             Token::Minus => ArithmeticOperator::Subtract,
             _ => break,
         };
 
+        let span = span_at(tokens, *i);
         *i += 1;
         let right = parse_multiplicative(tokens, i)?;
-        left = Expression::Arithmetic {
+        left = ExpressionKind::Arithmetic {
             left: Box::new(left),
             op,
             right: Box::new(right),
-        };
+        }
+        .at(span);
     }
 
     Ok(left)
 }
 
-fn parse_multiplicative(tokens: &[Token], i: &mut usize) -> ParseResult<Expression> {
+fn parse_multiplicative(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
     let mut left = parse_unary(tokens, i)?;
 
     while *i < tokens.len() {
-        let op = match &tokens[*i] {
+        let op = match &tokens[*i].token {
             Token::Star => ArithmeticOperator::Multiply,
             Token::Slash => ArithmeticOperator::Divide,
             Token::Percent => ArithmeticOperator::Modulo,
             _ => break,
         };
 
+        let span = span_at(tokens, *i);
         *i += 1;
         let right = parse_unary(tokens, i)?;
-        left = Expression::Arithmetic {
+        left = ExpressionKind::Arithmetic {
             left: Box::new(left),
             op,
             right: Box::new(right),
-        };
+        }
+        .at(span);
     }
 
     Ok(left)
 }
 
-fn parse_unary(tokens: &[Token], i: &mut usize) -> ParseResult<Expression> {
+fn parse_unary(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
     if *i >= tokens.len() {
         return Err(ParseError::UnexpectedEof {
             expected: "expression".to_string(),
+            span: span_at(tokens, *i),
         });
     }
 
     // Check for unary NOT operator
-    if matches!(tokens[*i], Token::Not) {
+    let span = span_at(tokens, *i);
+    if matches!(tokens[*i].token, Token::Not) {
         *i += 1; // skip '!'
         let operand = parse_unary(tokens, i)?;
-        return Ok(Expression::UnaryOp {
+        return Ok(ExpressionKind::UnaryOp {
             op: UnaryOperator::Not,
             operand: Box::new(operand),
-        });
+        }
+        .at(span));
+    }
+
+    if matches!(tokens[*i].token, Token::Minus) {
+        *i += 1; // skip '-'
+        let operand = parse_unary(tokens, i)?;
+        // Fold negative literals so `-5` is a plain number
+        return Ok(match operand.kind {
+            ExpressionKind::Integer(n) => ExpressionKind::Integer(-n),
+            ExpressionKind::Float(n) => ExpressionKind::Float(-n),
+            kind => ExpressionKind::UnaryOp {
+                op: UnaryOperator::Negate,
+                operand: Box::new(kind.at(operand.span)),
+            },
+        }
+        .at(span));
+    }
+
+    if matches!(tokens[*i].token, Token::Plus) {
+        *i += 1; // skip '+'
+        let operand = parse_unary(tokens, i)?;
+        return Ok(match operand.kind {
+            kind @ (ExpressionKind::Integer(_) | ExpressionKind::Float(_)) => kind,
+            kind => ExpressionKind::UnaryOp {
+                op: UnaryOperator::Plus,
+                operand: Box::new(kind.at(operand.span)),
+            },
+        }
+        .at(span));
     }
 
     parse_primary_expression(tokens, i)
 }
 
-fn parse_primary_expression(tokens: &[Token], i: &mut usize) -> ParseResult<Expression> {
+// Parse a parenthesized, comma-separated argument list starting at '('
+fn parse_arguments(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Vec<Expression>> {
+    *i += 1; // skip '('
+
+    let mut args = Vec::new();
+    while *i < tokens.len() && !matches!(tokens[*i].token, Token::RParen) {
+        let arg_expr = parse_expression(tokens, i)?;
+        args.push(arg_expr);
+
+        // Comma between arguments
+        if *i < tokens.len() && matches!(tokens[*i].token, Token::Comma) {
+            *i += 1;
+        } else {
+            break;
+        }
+    }
+
+    // Expect closing ')'
+    if *i < tokens.len() && matches!(tokens[*i].token, Token::RParen) {
+        *i += 1;
+    } else {
+        return Err(unexpected(tokens, *i, "')'"));
+    }
+
+    Ok(args)
+}
+
+// Parse the inside of `object[...]`: an index, or a slice `a..b`, `a..` or `..b`
+fn parse_subscript(object: Expression, tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
+    let object = Box::new(object);
+    let span = span_at(tokens, *i - 1); // the '[' just skipped
+
+    if matches!(token_at(tokens, *i), Some(Token::DotDot)) {
+        *i += 1; // skip '..'
+        let end = parse_arithmetic(tokens, i)?;
+        return Ok(ExpressionKind::Slice { object, start: None, end: Some(Box::new(end)) }.at(span));
+    }
+
+    // `a..]` isn't a complete range, so look for an open end before parsing the index
+    let mut j = *i;
+    if let Ok(start) = parse_arithmetic(tokens, &mut j) {
+        if matches!(token_at(tokens, j), Some(Token::DotDot)) && matches!(token_at(tokens, j + 1), Some(Token::RBracket)) {
+            *i = j + 1;
+            return Ok(ExpressionKind::Slice { object, start: Some(Box::new(start)), end: None }.at(span));
+        }
+    }
+
+    let index = parse_expression(tokens, i)?;
+    Ok(match index.kind {
+        ExpressionKind::Range { start, end } => ExpressionKind::Slice { object, start: Some(start), end: Some(end) },
+        kind => ExpressionKind::Index { object, index: Box::new(kind.at(index.span)) },
+    }
+    .at(span))
+}
+
+fn parse_primary_expression(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Expression> {
     if *i >= tokens.len() {
         return Err(ParseError::UnexpectedEof {
             expected: "expression".to_string(),
+            span: span_at(tokens, *i),
         });
     }
 
     // Handle parentheses
-    if matches!(tokens[*i], Token::LParen) {
+    if matches!(tokens[*i].token, Token::LParen) {
         *i += 1; // skip '('
         let expr = parse_expression(tokens, i)?;
 
-        if *i >= tokens.len() || !matches!(tokens[*i], Token::RParen) {
-            return Err(ParseError::UnexpectedToken {
-                expected: "')'".to_string(),
-                found: format!("{:?}", tokens.get(*i)),
-                position: *i,
-            });
+        if *i >= tokens.len() || !matches!(tokens[*i].token, Token::RParen) {
+            return Err(unexpected(tokens, *i, "')'"));
         }
         *i += 1; // skip ')'
         return Ok(expr);
     }
 
     // Base expression
-    let mut expr = match &tokens[*i] {
+    let span = span_at(tokens, *i);
+    let kind = match &tokens[*i].token {
         Token::String(s) => {
             *i += 1;
-            Expression::String(s.clone())
+            ExpressionKind::String(s.clone())
+        }
+        Token::Template(parts) => {
+            *i += 1;
+            let parts = parts
+                .iter()
+                .map(|part| match part {
+                    StringPart::Text(text) => Ok(TemplatePart::Text(text.clone())),
+                    StringPart::Interpolation(tokens) => parse_expression_tokens(tokens).map(TemplatePart::Expression),
+                })
+                .collect::<ParseResult<_>>()?;
+            ExpressionKind::Template(parts)
+        }
+        Token::Ident(name) if name == "is_set" && matches!(token_at(tokens, *i + 1), Some(Token::LParen)) => {
+            *i += 2;
+            let Some(Token::Ident(var) | Token::Variable(var)) = token_at(tokens, *i) else {
+                return Err(unexpected(tokens, *i, "variable name"));
+            };
+            *i += 1;
+            if !matches!(token_at(tokens, *i), Some(Token::RParen)) {
+                return Err(unexpected(tokens, *i, "')'"));
+            }
+            *i += 1;
+            ExpressionKind::IsSet(var.clone())
+        }
+        Token::Ident(name) if matches!(token_at(tokens, *i + 1), Some(Token::LParen)) => {
+            *i += 1;
+            let args = parse_arguments(tokens, i)?;
+            ExpressionKind::Call { name: name.clone(), args }
         }
         Token::Variable(v) | Token::Ident(v) => {
             *i += 1;
-            Expression::Variable(v.clone())
+            ExpressionKind::Variable(v.clone())
         }
-        Token::Number(n) => {
-            let value = n.parse().map_err(|_| ParseError::InvalidExpression { position: *i })?;
+        Token::Integer(n) => {
             *i += 1;
-            Expression::Number(value)
+            ExpressionKind::Integer(*n)
         }
-        _ => {
-            return Err(ParseError::UnexpectedToken {
-                expected: "expression".to_string(),
-                found: format!("{:?}", tokens.get(*i)),
-                position: *i,
-            });
+        Token::Float(n) => {
+            *i += 1;
+            ExpressionKind::Float(*n)
         }
-    };
+        Token::True => {
+            *i += 1;
+            ExpressionKind::Boolean(true)
+        }
+        Token::False => {
+            *i += 1;
+            ExpressionKind::Boolean(false)
+        }
+        Token::Null => {
+            *i += 1;
+            ExpressionKind::Null
+        }
+        Token::Regex(pattern) => {
+            // Compile once here so bad patterns are rejected before the server starts
+            let regex = Regex::new(pattern).map_err(|e| ParseError::InvalidRegex {
+                pattern: pattern.clone(),
+                message: e.to_string(),
+                span: span_at(tokens, *i),
+            })?;
+            *i += 1;
+            ExpressionKind::Regex(regex)
+        }
+        Token::LBracket => {
+            *i += 1; // skip '['
 
-    // Handle chained method calls: .method(...)
-    while *i < tokens.len() && matches!(tokens[*i], Token::Dot) {
-        *i += 1; // skip '.'
+            let mut items = Vec::new();
+            while *i < tokens.len() && !matches!(tokens[*i].token, Token::RBracket) {
+                items.push(parse_expression(tokens, i)?);
 
-        // Method name
-        let method = if let Some(Token::Ident(name)) = tokens.get(*i) {
-            name.clone()
-        } else {
-            return Err(ParseError::UnexpectedToken {
-                expected: "method name".to_string(),
-                found: format!("{:?}", tokens.get(*i)),
-                position: *i,
-            });
-        };
-        *i += 1;
+                // Comma between items
+                if *i < tokens.len() && matches!(tokens[*i].token, Token::Comma) {
+                    *i += 1;
+                } else {
+                    break;
+                }
+            }
 
-        // Optional parentheses for arguments
-        let mut args = Vec::new();
-        if *i < tokens.len() && matches!(tokens[*i], Token::LParen) {
-            *i += 1; // skip '('
+            if *i < tokens.len() && matches!(tokens[*i].token, Token::RBracket) {
+                *i += 1;
+            } else {
+                return Err(unexpected(tokens, *i, "']'"));
+            }
 
-            while *i < tokens.len() && !matches!(tokens[*i], Token::RParen) {
-                let arg_expr = parse_expression(tokens, i)?;
-                args.push(arg_expr);
+            ExpressionKind::List(items)
+        }
+        Token::LBrace => {
+            *i += 1; // skip '{'
+
+            let mut entries = Vec::new();
+            while *i < tokens.len() && !matches!(tokens[*i].token, Token::RBrace) {
+                let key = match &tokens[*i].token {
+                    Token::String(k) | Token::Ident(k) => ExpressionKind::String(k.clone()).at(span_at(tokens, *i)),
+                    _ => {
+                        return Err(unexpected(tokens, *i, "map key"));
+                    }
+                };
+                *i += 1;
 
-                // Comma between arguments
-                if *i < tokens.len() && matches!(tokens[*i], Token::Comma) {
+                if *i >= tokens.len() || !matches!(tokens[*i].token, Token::Colon) {
+                    return Err(unexpected(tokens, *i, "':'"));
+                }
+                *i += 1;
+
+                let value = parse_expression(tokens, i)?;
+                entries.push((key, value));
+
+                // Comma between entries
+                if *i < tokens.len() && matches!(tokens[*i].token, Token::Comma) {
                     *i += 1;
                 } else {
                     break;
                 }
             }
 
-            // Expect closing ')'
-            if *i < tokens.len() && matches!(tokens[*i], Token::RParen) {
+            if *i < tokens.len() && matches!(tokens[*i].token, Token::RBrace) {
                 *i += 1;
             } else {
-                return Err(ParseError::UnexpectedToken {
-                    expected: "')'".to_string(),
-                    found: format!("{:?}", tokens.get(*i)),
-                    position: *i,
-                });
+                return Err(unexpected(tokens, *i, "'}'"));
+            }
+
+            ExpressionKind::Map(entries)
+        }
+        _ => {
+            return Err(unexpected(tokens, *i, "expression"));
+        }
+    };
+    let mut expr = kind.at(span);
+
+    // Handle chained method calls and indexing: .method(...) and [index]
+    while *i < tokens.len() && matches!(tokens[*i].token, Token::Dot | Token::LBracket) {
+        if matches!(tokens[*i].token, Token::LBracket) {
+            *i += 1; // skip '['
+            expr = parse_subscript(expr, tokens, i)?;
+
+            if *i < tokens.len() && matches!(tokens[*i].token, Token::RBracket) {
+                *i += 1;
+            } else {
+                return Err(unexpected(tokens, *i, "']'"));
             }
+            continue;
         }
 
+        *i += 1; // skip '.'
+
+        // Method name
+        let span = span_at(tokens, *i);
+        let method = if let Some(Token::Ident(name)) = token_at(tokens, *i) {
+            name.clone()
+        } else {
+            return Err(unexpected(tokens, *i, "method name"));
+        };
+        *i += 1;
+
+        // Without parentheses this is a field access such as `user.name`
+        if *i >= tokens.len() || !matches!(tokens[*i].token, Token::LParen) {
+            expr = ExpressionKind::Field {
+                object: Box::new(expr),
+                name: method,
+            }
+            .at(span);
+            continue;
+        }
+
+        let mut args = parse_arguments(tokens, i)?;
+
         // Build MethodCall expression
         let arg = match args.len() {
             0 => None,
             1 => Some(Box::new(args.remove(0))),
-            _ => Some(Box::new(Expression::Tuple(args))),
+            _ => {
+                let span = args[0].span.clone();
+                Some(Box::new(ExpressionKind::Tuple(args).at(span)))
+            }
         };
 
-        expr = Expression::MethodCall {
+        expr = ExpressionKind::MethodCall {
             object: Box::new(expr),
             method,
             arg,
-        };
+        }
+        .at(span);
     }
 
     Ok(expr)
 }
 
-// Helper function to parse a single statement
-fn parse_single_statement(tokens: &[Token], i: &mut usize) -> ParseResult<Statement> {
-    if *i >= tokens.len() {
+// Parse the rest of an assignment after the variable name:
+// an optional `[key]` / `.field` path, then `= value`
+fn parse_assignment(name: String, tokens: &[SpannedToken], i: &mut usize) -> ParseResult<StatementKind> {
+    let mut path = Vec::new();
+
+    while *i < tokens.len() && matches!(tokens[*i].token, Token::LBracket | Token::Dot) {
+        if matches!(tokens[*i].token, Token::LBracket) {
+            *i += 1; // skip '['
+            path.push(parse_expression(tokens, i)?);
+
+            if *i >= tokens.len() || !matches!(tokens[*i].token, Token::RBracket) {
+                return Err(unexpected(tokens, *i, "']'"));
+            }
+            *i += 1;
+        } else {
+            *i += 1; // skip '.'
+            if let Some(Token::Ident(field)) = token_at(tokens, *i) {
+                path.push(ExpressionKind::String(field.clone()).at(span_at(tokens, *i)));
+                *i += 1;
+            } else {
+                return Err(unexpected(tokens, *i, "field name"));
+            }
+        }
+    }
+
+    if path.is_empty() {
+        if let Some(update) = parse_update(&name, tokens, i)? {
+            return Ok(update);
+        }
+    }
+
+    if *i >= tokens.len() || !matches!(tokens[*i].token, Token::Equals) {
+        return Err(unexpected(tokens, *i, "'='"));
+    }
+    *i += 1;
+
+    let value = parse_expression(tokens, i)?;
+    if path.is_empty() {
+        Ok(StatementKind::SetVar { name, value })
+    } else {
+        Ok(StatementKind::SetIndex { name, path, value })
+    }
+}
+
+/// Collect the `///` comments at `i` into one doc string
+fn parse_doc(tokens: &[SpannedToken], i: &mut usize) -> Option<String> {
+    let mut lines = Vec::new();
+    while let Some(Token::DocComment(line)) = token_at(tokens, *i) {
+        lines.push(line.as_str());
+        *i += 1;
+    }
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+/// Parse `+= value`, `-= value`, `*= value`, `/= value`, `++` or `--`
+/// after a variable name, if one follows
+fn parse_update(name: &str, tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Option<StatementKind>> {
+    let (op, step) = match token_at(tokens, *i) {
+        Some(Token::PlusEquals) => (ArithmeticOperator::Add, false),
+        Some(Token::MinusEquals) => (ArithmeticOperator::Subtract, false),
+        Some(Token::StarEquals) => (ArithmeticOperator::Multiply, false),
+        Some(Token::SlashEquals) => (ArithmeticOperator::Divide, false),
+        Some(Token::PlusPlus) => (ArithmeticOperator::Add, true),
+        Some(Token::MinusMinus) => (ArithmeticOperator::Subtract, true),
+        _ => return Ok(None),
+    };
+    let span = span_at(tokens, *i);
+    *i += 1;
+
+    let value = if step { ExpressionKind::Integer(1).at(span) } else { parse_expression(tokens, i)? };
+    Ok(Some(StatementKind::Update { name: name.to_string(), op, value }))
+}
+
+/// Record an error, unless one was already reported at the same place: an
+/// error that escapes several nested blocks is only reported once
+fn report(errors: &mut Vec<ParseError>, error: ParseError) {
+    if errors.last().is_none_or(|last| last.span() != error.span()) {
+        errors.push(error);
+    }
+}
+
+/// Whether the token at `i` is the first on its line
+fn starts_line(tokens: &[SpannedToken], i: usize) -> bool {
+    i == 0 || tokens[i - 1].span.line < tokens[i].span.line
+}
+
+fn is_statement_start(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Set
+            | Token::If
+            | Token::Match
+            | Token::While
+            | Token::For
+            | Token::Try
+            | Token::Log
+            | Token::Send
+            | Token::Return
+            | Token::Break
+            | Token::Continue
+            | Token::Ident(_)
+    )
+}
+
+fn is_declaration_start(token: &Token) -> bool {
+    matches!(token, Token::Server | Token::Fn | Token::Const | Token::Set | Token::Import | Token::DocComment(_))
+}
+
+/// After an error in something that started at `start`, skip to where parsing
+/// can resume: a token that begins a line and satisfies `resume`, the `}`
+/// closing the enclosing block, or the end of the file. Brackets opened along
+/// the way are skipped along with their contents.
+fn synchronize(tokens: &[SpannedToken], i: &mut usize, start: usize, resume: fn(&Token) -> bool) {
+    if *i <= start {
+        *i = start + 1;
+    }
+
+    // Blocks the failed statement opened before the error are skipped too.
+    // An unclosed '(' or '[' is more likely the error itself than something
+    // spanning the following statements.
+    let mut open = Vec::new();
+    for spanned in &tokens[start..*i] {
+        match spanned.token {
+            Token::LBrace | Token::LParen | Token::LBracket => open.push(&spanned.token),
+            Token::RBrace => while open.pop().is_some_and(|token| *token != Token::LBrace) {},
+            Token::RParen | Token::RBracket => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+    let mut depth = open.iter().filter(|token| ***token == Token::LBrace).count();
+
+    while let Some(token) = token_at(tokens, *i) {
+        match token {
+            Token::Eof => break,
+            Token::RBrace if depth == 0 => break,
+            _ if depth == 0 && starts_line(tokens, *i) && resume(token) => break,
+            Token::LBrace | Token::LParen | Token::LBracket => depth += 1,
+            Token::RBrace | Token::RParen | Token::RBracket => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        *i += 1;
+    }
+}
+
+/// Parse statements up to the `}` ending a block, recovering from errors
+/// in individual statements
+fn parse_statements(tokens: &[SpannedToken], i: &mut usize, errors: &mut Vec<ParseError>) -> Vec<Statement> {
+    let mut body = Vec::new();
+    while *i < tokens.len() && !matches!(tokens[*i].token, Token::RBrace | Token::Eof) {
+        // Doc comments only document declarations; in a block they are plain comments
+        if parse_doc(tokens, i).is_some() {
+            continue;
+        }
+        let start = *i;
+        match parse_single_statement(tokens, i, errors) {
+            Ok(stmt) => body.push(stmt),
+            Err(error) => {
+                report(errors, error);
+                synchronize(tokens, i, start, is_statement_start);
+            }
+        }
+    }
+    body
+}
+
+// Parse a `{ ... }` block of statements
+fn parse_block(tokens: &[SpannedToken], i: &mut usize, errors: &mut Vec<ParseError>) -> ParseResult<Vec<Statement>> {
+    if *i >= tokens.len() || !matches!(tokens[*i].token, Token::LBrace) {
+        return Err(unexpected(tokens, *i, "'{'"));
+    }
+    *i += 1;
+
+    let body = parse_statements(tokens, i, errors);
+
+    if *i >= tokens.len() || !matches!(tokens[*i].token, Token::RBrace) {
         return Err(ParseError::UnexpectedEof {
-            expected: "statement".to_string(),
+            expected: "'}'".to_string(),
+            span: span_at(tokens, *i),
         });
     }
+    *i += 1;
 
-    match &tokens[*i] {
-        Token::Ident(name) if *i + 1 < tokens.len() && matches!(tokens[*i + 1], Token::Equals) => {
-            let var_name = name.clone();
-            *i += 1;
-            *i += 1;
-            let value = parse_expression(&tokens, i)?;
-            Ok(Statement::SetVar { name: var_name, value })
+    Ok(body)
+}
+
+// Parse a match pattern, including `a | b` alternatives
+fn parse_pattern(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Pattern> {
+    let mut alternatives = vec![parse_single_pattern(tokens, i)?];
+
+    while *i < tokens.len() && matches!(tokens[*i].token, Token::Pipe) {
+        *i += 1; // skip '|'
+        alternatives.push(parse_single_pattern(tokens, i)?);
+    }
+
+    if alternatives.len() == 1 {
+        Ok(alternatives.remove(0))
+    } else {
+        Ok(Pattern::Alternatives(alternatives))
+    }
+}
+
+fn parse_single_pattern(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Pattern> {
+    let pattern = match token_at(tokens, *i) {
+        Some(Token::String(s)) if matches!(token_at(tokens, *i + 1), Some(Token::DotDot)) => {
+            *i += 2; // skip prefix and '..'
+            let rest = if let Some(Token::Ident(name)) = token_at(tokens, *i) {
+                *i += 1;
+                Some(name.clone())
+            } else {
+                None
+            };
+            return Ok(Pattern::Prefix { prefix: s.clone(), rest });
         }
-        Token::If => {
-            *i += 1;
-            let condition = parse_expression(&tokens, i)?;
+        Some(Token::String(s)) => Pattern::Literal(Value::String(s.clone())),
+        Some(Token::Integer(n)) => Pattern::Literal(Value::Integer(*n)),
+        Some(Token::Float(n)) => Pattern::Literal(Value::Float(*n)),
+        Some(Token::True) => Pattern::Literal(Value::Boolean(true)),
+        Some(Token::False) => Pattern::Literal(Value::Boolean(false)),
+        Some(Token::Null) => Pattern::Literal(Value::Null),
+        Some(Token::Minus) => match token_at(tokens, *i + 1) {
+            Some(Token::Integer(n)) => {
+                *i += 1;
+                Pattern::Literal(Value::Integer(-n))
+            }
+            Some(Token::Float(n)) => {
+                *i += 1;
+                Pattern::Literal(Value::Float(-n))
+            }
+            _ => {
+                return Err(unexpected(tokens, *i + 1, "number after '-' in pattern"));
+            }
+        },
+        Some(Token::Ident(name)) if name == "_" => Pattern::Wildcard,
+        Some(Token::Ident(name)) => Pattern::Binding(name.clone()),
+        _ => {
+            return Err(unexpected(tokens, *i, "pattern"));
+        }
+    };
+    *i += 1;
+    Ok(pattern)
+}
 
-            if *i >= tokens.len() || !matches!(tokens[*i], Token::LBrace) {
-                return Err(ParseError::UnexpectedToken {
-                    expected: "'{'".to_string(),
-                    found: format!("{:?}", tokens.get(*i)),
-                    position: *i,
-                });
+// Parse the `{ pattern [if guard] => body, ... }` arms of a match statement
+fn parse_match_arms(tokens: &[SpannedToken], i: &mut usize, errors: &mut Vec<ParseError>) -> ParseResult<Vec<MatchArm>> {
+    if *i >= tokens.len() || !matches!(tokens[*i].token, Token::LBrace) {
+        return Err(unexpected(tokens, *i, "'{'"));
+    }
+    *i += 1;
+
+    let mut arms = Vec::new();
+    while *i < tokens.len() && !matches!(tokens[*i].token, Token::RBrace | Token::Eof) {
+        if parse_doc(tokens, i).is_some() {
+            continue;
+        }
+        let start = *i;
+        match parse_match_arm(tokens, i, errors) {
+            Ok(arm) => arms.push(arm),
+            Err(error) => {
+                report(errors, error);
+                // Each arm starts on a line of its own
+                synchronize(tokens, i, start, |_| true);
             }
+        }
+
+        // Optional comma between arms
+        if *i < tokens.len() && matches!(tokens[*i].token, Token::Comma) {
             *i += 1;
+        }
+    }
 
-            let mut then_body = Vec::new();
-            while *i < tokens.len() && !matches!(tokens[*i], Token::RBrace) {
-                then_body.push(parse_single_statement(tokens, i)?);
-            }
+    if *i >= tokens.len() || !matches!(tokens[*i].token, Token::RBrace) {
+        return Err(ParseError::UnexpectedEof {
+            expected: "'}'".to_string(),
+            span: span_at(tokens, *i),
+        });
+    }
+    *i += 1;
 
-            if *i >= tokens.len() || !matches!(tokens[*i], Token::RBrace) {
-                return Err(ParseError::UnexpectedEof {
-                    expected: "'}'".to_string(),
-                });
+    Ok(arms)
+}
+
+fn parse_match_arm(tokens: &[SpannedToken], i: &mut usize, errors: &mut Vec<ParseError>) -> ParseResult<MatchArm> {
+    let span = span_at(tokens, *i);
+    let pattern = parse_pattern(tokens, i)?;
+
+    let guard = if *i < tokens.len() && matches!(tokens[*i].token, Token::If) {
+        *i += 1;
+        Some(parse_expression(tokens, i)?)
+    } else {
+        None
+    };
+
+    if *i >= tokens.len() || !matches!(tokens[*i].token, Token::FatArrow) {
+        return Err(unexpected(tokens, *i, "'=>'"));
+    }
+    *i += 1;
+
+    // Either a block or a single statement
+    let body = if *i < tokens.len() && matches!(tokens[*i].token, Token::LBrace) {
+        parse_block(tokens, i, errors)?
+    } else {
+        vec![parse_single_statement(tokens, i, errors)?]
+    };
+
+    Ok(MatchArm { pattern, span, guard, body })
+}
+
+/// Whether the name at `i` starts an assignment: past any `.field` and
+/// `[index]` accessors comes `=`, a compound assignment, `++` or `--`.
+/// Otherwise, as in `auth.login(user)`, it starts an expression.
+fn is_assignment(tokens: &[SpannedToken], i: usize) -> bool {
+    let mut j = i + 1;
+    loop {
+        match token_at(tokens, j) {
+            Some(Token::Dot) if matches!(token_at(tokens, j + 1), Some(Token::Ident(_))) => j += 2,
+            Some(Token::LBracket) => {
+                let mut depth = 0;
+                while let Some(token) = token_at(tokens, j) {
+                    match token {
+                        Token::LBracket => depth += 1,
+                        Token::RBracket => depth -= 1,
+                        _ => {}
+                    }
+                    j += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
             }
+            Some(
+                Token::Equals
+                | Token::PlusEquals
+                | Token::MinusEquals
+                | Token::StarEquals
+                | Token::SlashEquals
+                | Token::PlusPlus
+                | Token::MinusMinus,
+            ) => return true,
+            _ => return false,
+        }
+    }
+}
+
+// Helper function to parse a single statement
+fn parse_single_statement(
+    tokens: &[SpannedToken],
+    i: &mut usize,
+    errors: &mut Vec<ParseError>,
+) -> ParseResult<Statement> {
+    if *i >= tokens.len() {
+        return Err(ParseError::UnexpectedEof {
+            expected: "statement".to_string(),
+            span: span_at(tokens, *i),
+        });
+    }
+
+    let span = span_at(tokens, *i);
+    let kind = match &tokens[*i].token {
+        Token::Ident(name) if is_assignment(tokens, *i) => {
+            let var_name = name.clone();
             *i += 1;
+            parse_assignment(var_name, tokens, i)
+        }
+        Token::Ident(_) if matches!(token_at(tokens, *i + 1), Some(Token::LParen | Token::Dot | Token::LBracket)) => {
+            let expr = parse_expression(tokens, i)?;
+            Ok(StatementKind::Expression(expr))
+        }
+        Token::If => {
+            *i += 1;
+            let condition = parse_expression(tokens, i)?;
+            let then_body = parse_block(tokens, i, errors)?;
 
             // Parse else if chains
             let mut else_ifs = Vec::new();
-            while *i < tokens.len() && matches!(tokens[*i], Token::Else) {
+            while *i < tokens.len() && matches!(tokens[*i].token, Token::Else) {
                 // Peek ahead to see if it's "else if" or just "else"
-                if *i + 1 < tokens.len() && matches!(tokens[*i + 1], Token::If) {
+                if *i + 1 < tokens.len() && matches!(tokens[*i + 1].token, Token::If) {
                     *i += 1; // skip 'else'
                     *i += 1; // skip 'if'
 
-                    let else_if_condition = parse_expression(&tokens, i)?;
-
-                    if *i >= tokens.len() || !matches!(tokens[*i], Token::LBrace) {
-                        return Err(ParseError::UnexpectedToken {
-                            expected: "'{'".to_string(),
-                            found: format!("{:?}", tokens.get(*i)),
-                            position: *i,
-                        });
-                    }
-                    *i += 1;
-
-                    let mut else_if_body = Vec::new();
-                    while *i < tokens.len() && !matches!(tokens[*i], Token::RBrace) {
-                        else_if_body.push(parse_single_statement(tokens, i)?);
-                    }
-
-                    if *i >= tokens.len() || !matches!(tokens[*i], Token::RBrace) {
-                        return Err(ParseError::UnexpectedEof {
-                            expected: "'}'".to_string(),
-                        });
-                    }
-                    *i += 1;
-
+                    let else_if_condition = parse_expression(tokens, i)?;
+                    let else_if_body = parse_block(tokens, i, errors)?;
                     else_ifs.push((else_if_condition, else_if_body));
                 } else {
                     // It's just "else", not "else if"
@@ -380,282 +1038,411 @@ fn parse_single_statement(tokens: &[Token], i: &mut usize) -> ParseResult<Statem
             }
 
             // Parse final else block
-            let else_body = if *i < tokens.len() && matches!(tokens[*i], Token::Else) {
+            let else_body = if *i < tokens.len() && matches!(tokens[*i].token, Token::Else) {
                 *i += 1;
-
-                if *i >= tokens.len() || !matches!(tokens[*i], Token::LBrace) {
-                    return Err(ParseError::UnexpectedToken {
-                        expected: "'{'".to_string(),
-                        found: format!("{:?}", tokens.get(*i)),
-                        position: *i,
-                    });
-                }
-                *i += 1;
-
-                let mut else_stmts = Vec::new();
-                while *i < tokens.len() && !matches!(tokens[*i], Token::RBrace) {
-                    else_stmts.push(parse_single_statement(tokens, i)?);
-                }
-
-                if *i >= tokens.len() || !matches!(tokens[*i], Token::RBrace) {
-                    return Err(ParseError::UnexpectedEof {
-                        expected: "'}'".to_string(),
-                    });
-                }
-                *i += 1;
-
-                Some(else_stmts)
+                Some(parse_block(tokens, i, errors)?)
             } else {
                 None
             };
 
-            Ok(Statement::If { condition, then_body, else_ifs, else_body })
+            Ok(StatementKind::If { condition, then_body, else_ifs, else_body })
         }
         Token::Set => {
             *i += 1;
             if *i >= tokens.len() {
                 return Err(ParseError::UnexpectedEof {
                     expected: "variable name".to_string(),
+                    span: span_at(tokens, *i),
                 });
             }
 
-            let name = if let Token::Ident(n) = &tokens[*i] {
+            let name = if let Token::Ident(n) = &tokens[*i].token {
                 n.clone()
             } else {
-                return Err(ParseError::UnexpectedToken {
-                    expected: "variable name".to_string(),
-                    found: format!("{:?}", tokens[*i]),
-                    position: *i,
-                });
+                return Err(unexpected(tokens, *i, "variable name"));
             };
             *i += 1;
 
-            if *i >= tokens.len() || !matches!(tokens[*i], Token::Equals) {
-                return Err(ParseError::UnexpectedToken {
-                    expected: "'='".to_string(),
-                    found: format!("{:?}", tokens.get(*i)),
-                    position: *i,
-                });
+            parse_assignment(name, tokens, i)
+        }
+        Token::Log => {
+            *i += 1;
+            if *i >= tokens.len() || !matches!(tokens[*i].token, Token::LParen) {
+                return Err(unexpected(tokens, *i, "'('"));
+            }
+            *i += 1;
+
+            let expr = parse_expression(tokens, i)?;
+
+            if *i >= tokens.len() || !matches!(tokens[*i].token, Token::RParen) {
+                return Err(unexpected(tokens, *i, "')'"));
             }
             *i += 1;
 
-            let value = parse_expression(&tokens, i)?;
-            Ok(Statement::SetVar { name, value })
+            Ok(StatementKind::Log(expr))
         }
-        Token::Log => {
+        Token::Send => {
             *i += 1;
-            if *i >= tokens.len() || !matches!(tokens[*i], Token::LParen) {
-                return Err(ParseError::UnexpectedToken {
-                    expected: "'('".to_string(),
-                    found: format!("{:?}", tokens.get(*i)),
-                    position: *i,
-                });
+            if *i >= tokens.len() || !matches!(tokens[*i].token, Token::LParen) {
+                return Err(unexpected(tokens, *i, "'('"));
             }
             *i += 1;
 
-            let expr = parse_expression(&tokens, i)?;
+            let expr = parse_expression(tokens, i)?;
 
-            if *i >= tokens.len() || !matches!(tokens[*i], Token::RParen) {
-                return Err(ParseError::UnexpectedToken {
-                    expected: "')'".to_string(),
-                    found: format!("{:?}", tokens.get(*i)),
-                    position: *i,
-                });
+            if *i >= tokens.len() || !matches!(tokens[*i].token, Token::RParen) {
+                return Err(unexpected(tokens, *i, "')'"));
             }
             *i += 1;
 
-            Ok(Statement::Log(expr))
+            Ok(StatementKind::Send(expr))
         }
-        Token::Send => {
+        Token::While => {
             *i += 1;
-            if *i >= tokens.len() || !matches!(tokens[*i], Token::LParen) {
-                return Err(ParseError::UnexpectedToken {
-                    expected: "'('".to_string(),
-                    found: format!("{:?}", tokens.get(*i)),
-                    position: *i,
-                });
+            let condition = parse_expression(tokens, i)?;
+            let body = parse_block(tokens, i, errors)?;
+            Ok(StatementKind::While { condition, body })
+        }
+        Token::For => {
+            *i += 1;
+            let var = if let Some(Token::Ident(name)) = token_at(tokens, *i) {
+                name.clone()
+            } else {
+                return Err(unexpected(tokens, *i, "loop variable name"));
+            };
+            *i += 1;
+
+            if *i >= tokens.len() || !matches!(tokens[*i].token, Token::In) {
+                return Err(unexpected(tokens, *i, "'in'"));
             }
             *i += 1;
 
-            let expr = parse_expression(&tokens, i)?;
+            let iterable = parse_expression(tokens, i)?;
+            let body = parse_block(tokens, i, errors)?;
+            Ok(StatementKind::For { var, iterable, body })
+        }
+        Token::Try => {
+            *i += 1;
+            let body = parse_block(tokens, i, errors)?;
 
-            if *i >= tokens.len() || !matches!(tokens[*i], Token::RParen) {
-                return Err(ParseError::UnexpectedToken {
-                    expected: "')'".to_string(),
-                    found: format!("{:?}", tokens.get(*i)),
-                    position: *i,
-                });
+            if !matches!(token_at(tokens, *i), Some(Token::Catch)) {
+                return Err(unexpected(tokens, *i, "'catch' after try block"));
             }
             *i += 1;
 
-            Ok(Statement::Send(expr))
+            // The error binding is optional: `catch { }` ignores the message
+            let catch_var = if let Some(Token::Ident(name)) = token_at(tokens, *i) {
+                *i += 1;
+                Some(name.clone())
+            } else {
+                None
+            };
+            let catch_body = parse_block(tokens, i, errors)?;
+
+            Ok(StatementKind::Try { body, catch_var, catch_body })
+        }
+        Token::Match => {
+            *i += 1;
+            let subject = parse_expression(tokens, i)?;
+            let arms = parse_match_arms(tokens, i, errors)?;
+            Ok(StatementKind::Match { subject, arms })
+        }
+        Token::Break => {
+            *i += 1;
+            Ok(StatementKind::Break)
+        }
+        Token::Continue => {
+            *i += 1;
+            Ok(StatementKind::Continue)
+        }
+        Token::Return => {
+            *i += 1;
+            if *i >= tokens.len() || matches!(tokens[*i].token, Token::RBrace | Token::Eof) {
+                return Ok(StatementKind::Return(None).at(span));
+            }
+            let value = parse_expression(tokens, i)?;
+            Ok(StatementKind::Return(Some(value)))
         }
         _ => {
-            Err(ParseError::UnexpectedToken {
-                expected: "statement (set, if, log, send)".to_string(),
-                found: format!("{:?}", tokens[*i]),
-                position: *i,
-            })
+            Err(misspelled(tokens, *i, "statement (set, if, match, while, for, try, log, send, return)", &STATEMENT_KEYWORDS))
+        }
+    }?;
+    Ok(kind.at(span))
+}
+
+// Parse `fn name(a, b) { ... }`, starting after the `fn` keyword
+fn parse_function(
+    tokens: &[SpannedToken],
+    i: &mut usize,
+    doc: Option<String>,
+    errors: &mut Vec<ParseError>,
+) -> ParseResult<Statement> {
+    let span = span_at(tokens, *i - 1); // the 'fn' just skipped
+    let name = if let Some(Token::Ident(n)) = token_at(tokens, *i) {
+        n.clone()
+    } else {
+        return Err(unexpected(tokens, *i, "function name"));
+    };
+    *i += 1;
+
+    if *i >= tokens.len() || !matches!(tokens[*i].token, Token::LParen) {
+        return Err(unexpected(tokens, *i, "'('"));
+    }
+    *i += 1;
+
+    let mut params = Vec::new();
+    while let Some(Token::Ident(param)) = token_at(tokens, *i) {
+        params.push(param.clone());
+        *i += 1;
+
+        // Comma between parameters
+        if *i < tokens.len() && matches!(tokens[*i].token, Token::Comma) {
+            *i += 1;
+        } else {
+            break;
         }
     }
+
+    if *i >= tokens.len() || !matches!(tokens[*i].token, Token::RParen) {
+        return Err(unexpected(tokens, *i, "parameter name or ')'"));
+    }
+    *i += 1;
+
+    let body = parse_block(tokens, i, errors)?;
+    Ok(StatementKind::Function { name, params, doc, body }.at(span))
 }
 
-pub fn parse(tokens: Vec<Token>) -> Result<Vec<Statement>, ParseError> {
-    let mut stmts = Vec::new();
-    let mut i = 0;
+/// Parse a `const NAME = value` declaration after the `const` keyword
+fn parse_const(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Statement> {
+    let span = span_at(tokens, *i - 1); // the 'const' just skipped
+    let name = if let Some(Token::Ident(n)) = token_at(tokens, *i) {
+        n.clone()
+    } else {
+        return Err(unexpected(tokens, *i, "constant name"));
+    };
+    *i += 1;
 
-    while i < tokens.len() {
-        match &tokens[i] {
-            Token::Server => {
-                i += 1;
+    if *i >= tokens.len() || !matches!(tokens[*i].token, Token::Equals) {
+        return Err(unexpected(tokens, *i, "'='"));
+    }
+    *i += 1;
 
-                if i >= tokens.len() {
-                    return Err(ParseError::UnexpectedEof {
-                        expected: "protocol (tcp)".to_string(),
-                    });
-                }
+    let value = parse_expression(tokens, i)?;
+    Ok(StatementKind::Const { name, value }.at(span))
+}
 
-                if !matches!(tokens[i], Token::TCP) {
-                    return Err(ParseError::UnexpectedToken {
-                        expected: "tcp".to_string(),
-                        found: format!("{:?}", tokens[i]),
-                        position: i,
-                    });
-                }
-                i += 1;
+/// Parse `import "path"` or `import "path" as name` after the `import` keyword
+fn parse_import(tokens: &[SpannedToken], i: &mut usize) -> ParseResult<Statement> {
+    let span = span_at(tokens, *i - 1); // the 'import' just skipped
+    let path = if let Some(Token::String(p)) = token_at(tokens, *i) {
+        p.clone()
+    } else {
+        return Err(unexpected(tokens, *i, "import path string"));
+    };
+    *i += 1;
 
-                if i >= tokens.len() {
-                    return Err(ParseError::UnexpectedEof {
-                        expected: "port string".to_string(),
-                    });
-                }
+    let alias = if let Some(Token::As) = token_at(tokens, *i) {
+        *i += 1;
+        if let Some(Token::Ident(name)) = token_at(tokens, *i) {
+            *i += 1;
+            Some(name.clone())
+        } else {
+            return Err(unexpected(tokens, *i, "module name after 'as'"));
+        }
+    } else {
+        None
+    };
 
-                let port = if let Token::String(p) = &tokens[i] {
-                    p.clone()
-                } else {
-                    return Err(ParseError::UnexpectedToken {
-                        expected: "port string".to_string(),
-                        found: format!("{:?}", tokens[i]),
-                        position: i,
-                    });
-                };
-                i += 1;
+    Ok(StatementKind::Import { path, alias }.at(span))
+}
 
-                if i >= tokens.len() {
-                    return Err(ParseError::UnexpectedEof {
-                        expected: "'{'".to_string(),
-                    });
-                }
+/// Parse `server tcp "port" { ... }` after the `server` keyword
+fn parse_server(
+    tokens: &[SpannedToken],
+    i: &mut usize,
+    doc: Option<String>,
+    errors: &mut Vec<ParseError>,
+) -> ParseResult<Statement> {
+    let span = span_at(tokens, *i - 1); // the 'server' just skipped
+    if !matches!(token_at(tokens, *i), Some(Token::Tcp)) {
+        return Err(misspelled(tokens, *i, "'tcp'", &["tcp"]));
+    }
+    *i += 1;
 
-                if !matches!(tokens[i], Token::LBrace) {
-                    return Err(ParseError::UnexpectedToken {
-                        expected: "'{'".to_string(),
-                        found: format!("{:?}", tokens[i]),
-                        position: i,
-                    });
-                }
-                i += 1;
+    let port = if let Some(Token::String(p)) = token_at(tokens, *i) {
+        p.clone()
+    } else {
+        return Err(unexpected(tokens, *i, "port string"));
+    };
+    *i += 1;
 
-                let mut body = Vec::new();
-
-                while i < tokens.len() && !matches!(tokens[i], Token::RBrace | Token::EOF) {
-                    if let Token::On = tokens[i] {
-                        i += 1;
-
-                        if i >= tokens.len() {
-                            return Err(ParseError::UnexpectedEof {
-                                expected: "event name".to_string(),
-                            });
-                        }
-
-                        let event = if let Token::Ident(e) = &tokens[i] {
-                            e.clone()
-                        } else {
-                            return Err(ParseError::UnexpectedToken {
-                                expected: "event name (connect, message, disconnect)".to_string(),
-                                found: format!("{:?}", tokens[i]),
-                                position: i,
-                            });
-                        };
-                        i += 1;
-
-                        if i >= tokens.len() {
-                            return Err(ParseError::UnexpectedEof {
-                                expected: "'{'".to_string(),
-                            });
-                        }
-
-                        if !matches!(tokens[i], Token::LBrace) {
-                            return Err(ParseError::UnexpectedToken {
-                                expected: "'{'".to_string(),
-                                found: format!("{:?}", tokens[i]),
-                                position: i,
-                            });
-                        }
-                        i += 1;
-
-                        let mut inner = Vec::new();
-
-                        // Parse event body using helper function
-                        while i < tokens.len() && !matches!(tokens[i], Token::RBrace | Token::EOF) {
-                            inner.push(parse_single_statement(&tokens, &mut i)?);
-                        }
-
-                        if i >= tokens.len() {
-                            return Err(ParseError::UnexpectedEof {
-                                expected: "'}'".to_string(),
-                            });
-                        }
-
-                        if matches!(tokens[i], Token::RBrace) {
-                            i += 1;
-                        } else {
-                            return Err(ParseError::UnexpectedToken {
-                                expected: "'}'".to_string(),
-                                found: format!("{:?}", tokens[i]),
-                                position: i,
-                            });
-                        }
-
-                        body.push(Statement::On {
-                            event,
-                            body: inner,
-                        });
-                    } else {
-                        return Err(ParseError::UnexpectedToken {
-                            expected: "'on' or '}'".to_string(),
-                            found: format!("{:?}", tokens[i]),
-                            position: i,
-                        });
-                    }
-                }
+    if !matches!(token_at(tokens, *i), Some(Token::LBrace)) {
+        return Err(unexpected(tokens, *i, "'{'"));
+    }
+    *i += 1;
+
+    let mut body = Vec::new();
+    while *i < tokens.len() && !matches!(tokens[*i].token, Token::RBrace | Token::Eof) {
+        let handler_doc = parse_doc(tokens, i);
+        let start = *i;
+        let item = match &tokens[*i].token {
+            Token::On => {
+                *i += 1;
+                parse_handler(tokens, i, handler_doc, errors)
+            }
+            Token::Fn => {
+                *i += 1;
+                parse_function(tokens, i, handler_doc, errors)
+            }
+            // Doc comments before the closing brace document nothing
+            Token::RBrace | Token::Eof => continue,
+            _ => Err(misspelled(tokens, *i, "'on', 'fn' or '}'", &["on", "fn"])),
+        };
+        match item {
+            Ok(item) => body.push(item),
+            Err(error) => {
+                report(errors, error);
+                synchronize(tokens, i, start, |token| matches!(token, Token::On | Token::Fn | Token::DocComment(_)));
+            }
+        }
+    }
 
-                if i >= tokens.len() {
-                    return Err(ParseError::UnexpectedEof {
-                        expected: "'}'".to_string(),
-                    });
-                }
+    if !matches!(token_at(tokens, *i), Some(Token::RBrace)) {
+        return Err(ParseError::UnexpectedEof {
+            expected: "'}'".to_string(),
+            span: span_at(tokens, *i),
+        });
+    }
+    *i += 1;
 
-                if matches!(tokens[i], Token::RBrace) {
-                    i += 1;
-                }
+    Ok(StatementKind::Server { protocol: "tcp".into(), port, doc, body }.at(span))
+}
 
-                stmts.push(Statement::Server {
-                    protocol: "tcp".into(),
-                    port,
-                    body,
-                });
+/// Parse `on event { ... }` after the `on` keyword
+fn parse_handler(
+    tokens: &[SpannedToken],
+    i: &mut usize,
+    doc: Option<String>,
+    errors: &mut Vec<ParseError>,
+) -> ParseResult<Statement> {
+    let span = span_at(tokens, *i - 1); // the 'on' just skipped
+    let expected = "event name (connect, message, disconnect, error)";
+    let event = if let Some(Token::Ident(e)) = token_at(tokens, *i) {
+        e.clone()
+    } else {
+        return Err(unexpected(tokens, *i, expected));
+    };
+    // An unknown event is reported, but its body is still parsed for errors
+    if !EVENTS.contains(&event.as_str()) {
+        report(errors, misspelled(tokens, *i, expected, &EVENTS));
+    }
+    *i += 1;
+
+    let body = parse_block(tokens, i, errors)?;
+    Ok(StatementKind::On { event, doc, body }.at(span))
+}
+
+/// Parse a whole file. Errors are recovered from at statement, handler and
+/// declaration boundaries, so every error in the file is returned, along with
+/// the program as far as it could be parsed.
+pub fn parse(tokens: Vec<SpannedToken>) -> (Vec<Statement>, Vec<ParseError>) {
+    let mut stmts = Vec::new();
+    let mut errors = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let doc = parse_doc(&tokens, &mut i);
+        let start = i;
+        let parsed = match &tokens[i].token {
+            Token::Server => {
+                i += 1;
+                parse_server(&tokens, &mut i, doc, &mut errors)
             }
-            Token::EOF => break,
-            _ => {
-                return Err(ParseError::UnexpectedToken {
-                    expected: "'server' declaration".to_string(),
-                    found: format!("{:?}", tokens[i]),
-                    position: i,
-                });
+            Token::Fn => {
+                i += 1;
+                parse_function(&tokens, &mut i, doc, &mut errors)
+            }
+            Token::Const => {
+                i += 1;
+                parse_const(&tokens, &mut i)
+            }
+            Token::Import => {
+                i += 1;
+                parse_import(&tokens, &mut i)
+            }
+            // File-scope `set` declares a global shared by every server
+            Token::Set => parse_single_statement(&tokens, &mut i, &mut errors),
+            Token::Eof => break,
+            _ => Err(misspelled(
+                &tokens,
+                i,
+                "'server', 'fn', 'const', 'set' or 'import' declaration",
+                &["server", "fn", "const", "set", "import"],
+            )),
+        };
+        match parsed {
+            Ok(stmt) => stmts.push(stmt),
+            Err(error) => {
+                report(&mut errors, error);
+                synchronize(&tokens, &mut i, start, is_declaration_start);
             }
         }
     }
 
-    Ok(stmts)
-}
\ No newline at end of file
+    (stmts, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, ParseError};
+    use crate::ast::{Statement, StatementKind};
+    use crate::lexer;
+
+    fn parse_source(source: &str) -> (Vec<Statement>, Vec<ParseError>) {
+        parse(lexer::lex("test.vi", source).expect("source should lex"))
+    }
+
+    /// Line and column of each error, in the order they were reported
+    fn positions(errors: &[ParseError]) -> Vec<(usize, usize)> {
+        errors.iter().map(|error| (error.span().line, error.span().column)).collect()
+    }
+
+    #[test]
+    fn member_calls_are_statements() {
+        let (program, errors) =
+            parse_source("fn handler() {\n    auth.login($message)\n    xs[0].trim()\n    m.a = 1\n    m[\"b\"] += 1\n}\n");
+        assert_eq!(positions(&errors), [(5, 12)]);
+        let StatementKind::Function { body, .. } = &program[0].kind else {
+            panic!("expected a function");
+        };
+        assert!(matches!(body[0].kind, StatementKind::Expression(_)));
+        assert!(matches!(body[1].kind, StatementKind::Expression(_)));
+        assert!(matches!(body[2].kind, StatementKind::SetIndex { .. }));
+    }
+
+    #[test]
+    fn recovers_at_statement_boundaries() {
+        let (_, errors) = parse_source(
+            "server tcp \":9026\" {\n    on message {\n        retrun 5\n        send(\"ok\")\n        \
+             set y = (1 + 2\n        send(\"still parsed\")\n        log(1 +)\n    }\n}\n",
+        );
+        assert_eq!(positions(&errors), [(3, 9), (6, 9), (7, 16)]);
+    }
+
+    #[test]
+    fn recovers_at_declaration_boundaries() {
+        let (program, errors) = parse_source(
+            "sever tcp \":9026\" {\n    on message { send(\"a\") }\n}\nconst = 5\n\
+             server tcp \":9027\" {\n    on message { send(\"b\") }\n}\n",
+        );
+        assert_eq!(positions(&errors), [(1, 1), (4, 7)]);
+        assert!(program.iter().any(|stmt| matches!(&stmt.kind, StatementKind::Server { port, .. } if port == ":9027")));
+    }
+
+    #[test]
+    fn suggests_misspelled_keywords() {
+        let (_, errors) = parse_source("server tcp \":9026\" {\n    on mesage { send(\"a\") }\n}\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].diagnostic().help.as_deref(), Some("did you mean `message`?"));
+    }
+}
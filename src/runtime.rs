@@ -1,451 +1,1399 @@
-use crate::ast::{Statement, Expression, BinaryOperator, LogicalOperator, UnaryOperator, ArithmeticOperator};
-use tokio::net::TcpListener;
+use crate::ast::{Statement, StatementKind, Expression, ExpressionKind, BinaryOperator, LogicalOperator, UnaryOperator, ArithmeticOperator, Pattern};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use tokio::sync::RwLock;
+use crate::diagnostic::Diagnostic;
 use crate::template::eval_template;
+use crate::token::Span;
+use crate::value::Value;
+use crate::methods::{self, Implementation, Method};
+use regex::Regex;
 
-type Variables = Arc<RwLock<HashMap<String, String>>>;
+type Variables = Arc<RwLock<HashMap<String, Value>>>;
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+pub type EvalResult = Result<Value, RuntimeError>;
+type HandlerResult<'a> = BoxFuture<'a, Result<Flow, RuntimeError>>;
 
-/// Extract `On` events from the server body
-fn extract_events(body: &[Statement]) -> Vec<Statement> {
-    body.iter().filter_map(|stmt| {
-        if let Statement::On { event, body } = stmt {
-            Some(Statement::On { event: event.clone(), body: body.clone() })
-        } else { None }
-    }).collect()
+/// Nested function calls allowed before a call is rejected
+const MAX_CALL_DEPTH: usize = 64;
+
+/// Iterations a single loop may run before it is stopped
+const MAX_LOOP_ITERATIONS: usize = 100_000;
+
+/// Loops hand control back to the scheduler this often so other connections keep running
+const LOOP_YIELD_INTERVAL: usize = 1_000;
+
+/// What went wrong while running a script
+#[derive(Debug, Clone)]
+pub enum ErrorKind {
+    /// A value had the wrong type for an operator, conversion or statement
+    Type(String),
+    /// A method or function was called with the wrong arguments
+    Argument { name: String, message: String },
+    UnknownFunction(String),
+    /// `suggestion` is a defined method the name looks like a misspelling of
+    UnknownMethod { method: String, type_name: &'static str, suggestion: Option<&'static str> },
+    DivisionByZero,
+    Overflow(String),
+    /// A list index or map key that does not exist
+    Index(String),
+    /// Assignment to a constant or into an undefined variable
+    Assignment(String),
+    /// A call depth, loop or range limit was exceeded
+    Limit(String),
+    /// Writing to the client failed
+    Io(String),
 }
 
-/// Central helper to apply methods to a string
-fn apply_method(
-    base: &str,
-    method: &str,
-    arg: Option<&Expression>,
-    message: Option<&str>,
-    client: Option<&str>,
-    vars: &HashMap<String, String>,
-) -> String {
-    match method {
-        "reverse" => base.chars().rev().collect(),
-        "upper" => base.to_uppercase(),
-        "lower" => base.to_lowercase(),
-        "length" | "len" => base.len().to_string(),
-        "capitalize" | "cap" => base.chars()
-            .next()
-            .map(|c| c.to_uppercase().collect::<String>() + &base[1..])
-            .unwrap_or_default(),
-        "contains" => match arg {
-            Some(Expression::String(arg)) if arg != "" => {
-                base.contains(arg).to_string()
-            }
-            Some(_) => {
-                eprintln!("Warning: contains requires 1 argument, got {:?}", arg);
-                base.to_string()
-            }
-            None => {
-                eprintln!("Warning: contains called without arguments");
-                base.to_string()
-            }
-        },
-        "starts_with" => match arg {
-            Some(Expression::String(arg)) if arg != "" => {
-                base.starts_with(arg).to_string()
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Type(message)
+            | ErrorKind::Index(message)
+            | ErrorKind::Assignment(message)
+            | ErrorKind::Limit(message) => write!(f, "{}", message),
+            ErrorKind::Argument { name, message } => write!(f, "{}: {}", name, message),
+            ErrorKind::UnknownFunction(name) => write!(f, "unknown function '{}'", name),
+            ErrorKind::UnknownMethod { method, type_name, .. } => {
+                write!(f, "method '{}' is not defined for {}", method, type_name)
             }
-            Some(_) => {
-                eprintln!("Warning: starts_with requires 1 argument, got {:?}", arg);
-                base.to_string()
-            }
-            None => {
-                eprintln!("Warning: starts_with called without arguments");
-                base.to_string()
-            }
-        },
-        "ends_with" => match arg {
-            Some(Expression::String(arg)) if arg != "" => {
-                base.ends_with(arg).to_string()
-            }
-            Some(_) => {
-                eprintln!("Warning: ends_with requires 1 argument, got {:?}", arg);
-                base.to_string()
-            }
-            None => {
-                eprintln!("Warning: ends_with called without arguments");
-                base.to_string()
-            }
-        },
-        "find" => match arg {
-            Some(Expression::String(arg)) if arg != "" => {
-                base.find(arg)
-                    .map(|i| i.to_string())
-                    .unwrap_or_else(|| "-1".to_string())
+            ErrorKind::DivisionByZero => write!(f, "division by zero"),
+            ErrorKind::Overflow(operation) => write!(f, "integer overflow in {}", operation),
+            ErrorKind::Io(message) => write!(f, "send failed: {}", message),
+        }
+    }
+}
+
+/// An error raised while running a script, tagged with the statement it escaped from
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    /// Source form of the innermost statement that failed
+    pub statement: Option<String>,
+    /// Where the innermost expression or statement that failed starts
+    pub span: Option<Span>,
+}
+
+impl RuntimeError {
+    /// Tag the error with the statement it escaped from, unless a more
+    /// deeply nested one already claimed it
+    fn escaping(mut self, stmt: &Statement) -> Self {
+        self.statement.get_or_insert_with(|| stmt.to_string());
+        self.span.get_or_insert_with(|| stmt.span.clone());
+        self
+    }
+
+    /// The error as a diagnostic, when it is known where it happened
+    pub fn diagnostic(&self) -> Option<Diagnostic> {
+        let span = self.span.clone()?;
+        Some(Diagnostic { message: self.kind.to_string(), span, help: self.kind.help() })
+    }
+}
+
+impl ErrorKind {
+    /// A hint for fixing the error, such as the method a misspelled name meant
+    pub fn help(&self) -> Option<String> {
+        match self {
+            ErrorKind::UnknownMethod { suggestion: Some(name), .. } => Some(format!("did you mean `{}`?", name)),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorKind> for RuntimeError {
+    fn from(kind: ErrorKind) -> Self {
+        RuntimeError { kind, statement: None, span: None }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.span {
+            Some(span) => write!(f, "{}: {}", span, self.kind),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+pub fn type_error(message: impl Into<String>) -> RuntimeError {
+    ErrorKind::Type(message.into()).into()
+}
+
+pub fn argument_error(name: &str, message: impl Into<String>) -> RuntimeError {
+    ErrorKind::Argument { name: name.to_string(), message: message.into() }.into()
+}
+
+/// Print an error that reached the top of a handler or declaration
+fn report_error(origin: &str, label: &str, error: &RuntimeError) {
+    eprintln!("[{}] {}: {}", origin, label, error);
+    if let Some(statement) = &error.statement {
+        eprintln!("    in: {}", statement);
+    }
+    if let Some(help) = error.kind.help() {
+        eprintln!("    help: {}", help);
+    }
+}
+
+/// A user-defined `fn` declaration
+struct Function {
+    params: Vec<String>,
+    body: Vec<Statement>,
+    /// Module the function was declared in, for files imported with `as`
+    module: Option<String>,
+}
+
+type Functions = HashMap<String, Function>;
+
+/// Name of `name` as declared inside `module`
+fn qualify(module: Option<&str>, name: &str) -> String {
+    match module {
+        Some(module) => format!("{}.{}", module, name),
+        None => name.to_string(),
+    }
+}
+
+/// Top-level statements paired with the module they were declared in
+pub fn module_items(program: &[Statement]) -> Vec<(Option<&str>, &Statement)> {
+    program
+        .iter()
+        .flat_map(|stmt| match &stmt.kind {
+            StatementKind::Module { name, body } => body.iter().map(|s| (Some(name.as_str()), s)).collect(),
+            _ => vec![(None, stmt)],
+        })
+        .collect()
+}
+
+/// How a block of statements finished executing
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+    Return(Value),
+}
+
+/// Counts loop iterations, yielding to the scheduler periodically and
+/// stopping loops that run away
+struct LoopGuard {
+    iterations: usize,
+}
+
+impl LoopGuard {
+    fn new() -> Self {
+        LoopGuard { iterations: 0 }
+    }
+
+    /// Fails once the loop has used up its iteration budget
+    async fn tick(&mut self) -> Result<(), RuntimeError> {
+        self.iterations += 1;
+        if self.iterations > MAX_LOOP_ITERATIONS {
+            let message = format!("loop stopped after {} iterations", MAX_LOOP_ITERATIONS);
+            return Err(ErrorKind::Limit(message).into());
+        }
+        if self.iterations.is_multiple_of(LOOP_YIELD_INTERVAL) {
+            tokio::task::yield_now().await;
+        }
+        Ok(())
+    }
+}
+
+/// File-scope `const` and `set` declarations, shared by every server and connection
+#[derive(Clone, Default)]
+pub struct Globals {
+    constants: Arc<HashMap<String, Value>>,
+    vars: Variables,
+}
+
+impl Globals {
+    /// Evaluate the file-scope declarations in order. Later declarations can
+    /// read earlier ones and call top-level functions. In strict mode the
+    /// first failing declaration stops startup.
+    pub async fn init(shared: &[Statement], strict: bool) -> Result<Globals, RuntimeError> {
+        let functions = extract_functions(shared, &[], None);
+        let scratch: Variables = Arc::new(RwLock::new(HashMap::new()));
+        let building = Globals::default();
+        let mut constant_names = Vec::new();
+
+        let mut ctx = Context::new(None, "global".to_string(), None, None, scratch, &functions, &building)
+            .with_strict(strict);
+        for (module, stmt) in module_items(shared) {
+            ctx.module = module.map(str::to_string);
+            match &stmt.kind {
+                StatementKind::Const { name, value } | StatementKind::SetVar { name, value } => {
+                    let evaluated = match eval_expression(value, &mut ctx).await {
+                        Ok(evaluated) => evaluated,
+                        Err(error) => {
+                            let error = error.escaping(stmt);
+                            if strict {
+                                return Err(error);
+                            }
+                            report_error("global", "Warning", &error);
+                            continue;
+                        }
+                    };
+                    let name = qualify(module, name);
+                    let kind = if matches!(stmt.kind, StatementKind::Const { .. }) {
+                        constant_names.push(name.clone());
+                        "CONST"
+                    } else {
+                        "SET"
+                    };
+                    println!("[global] {}: {} = {}", kind, name, evaluated);
+                    building.vars.write().await.insert(name, evaluated);
+                }
+                StatementKind::Function { .. } | StatementKind::Server { .. } => {}
+                _ => {
+                    execute_statements(std::slice::from_ref(stmt), &mut ctx).await?;
+                }
             }
-            Some(_) => {
-                eprintln!("Warning: find requires 1 argument, got {:?}", arg);
-                base.to_string()
+        }
+        drop(ctx);
+
+        // Everything was stored in one map while evaluating; move constants out
+        // so they can no longer be assigned
+        let mut vars_write = building.vars.write().await;
+        let constants = constant_names
+            .into_iter()
+            .filter_map(|name| vars_write.remove(&name).map(|value| (name, value)))
+            .collect();
+        drop(vars_write);
+
+        Ok(Globals { constants: Arc::new(constants), vars: building.vars })
+    }
+
+    async fn get(&self, name: &str) -> Option<Value> {
+        if let Some(value) = self.vars.read().await.get(name) {
+            return Some(value.clone());
+        }
+        self.constants.get(name).cloned()
+    }
+
+    async fn contains_var(&self, name: &str) -> bool {
+        self.vars.read().await.contains_key(name)
+    }
+}
+
+/// State shared by the statements and expressions run for a single event
+pub struct Context<'a> {
+    /// The client connection; absent while file-scope declarations are evaluated
+    socket: Option<&'a mut TcpStream>,
+    /// Prefix for log output, usually the client address
+    origin: String,
+    message: Option<&'a str>,
+    client: Option<u16>,
+    vars: Variables,
+    functions: &'a Functions,
+    globals: &'a Globals,
+    /// Module of the code currently executing, for files imported with `as`
+    module: Option<String>,
+    /// Local scope of the function currently executing, if any
+    locals: Option<HashMap<String, Value>>,
+    depth: usize,
+    /// Whether errors abort the handler instead of skipping the failing statement
+    strict: bool,
+    /// Number of enclosing `try` blocks
+    catching: usize,
+    /// Message of the error being handled by an `on error` handler
+    error: Option<String>,
+}
+
+impl<'a> Context<'a> {
+    fn new(
+        socket: Option<&'a mut TcpStream>,
+        origin: String,
+        message: Option<&'a str>,
+        client: Option<u16>,
+        vars: Variables,
+        functions: &'a Functions,
+        globals: &'a Globals,
+    ) -> Self {
+        Context {
+            socket,
+            origin,
+            message,
+            client,
+            vars,
+            functions,
+            globals,
+            module: None,
+            locals: None,
+            depth: 0,
+            strict: false,
+            catching: 0,
+            error: None,
+        }
+    }
+
+    fn with_module(mut self, module: Option<String>) -> Self {
+        self.module = module;
+        self
+    }
+
+    fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Global name `name` refers to, preferring the current module's own declaration
+    async fn global_name<'n>(&self, name: &'n str) -> Cow<'n, str> {
+        if let Some(module) = &self.module {
+            let qualified = qualify(Some(module), name);
+            if self.globals.constants.contains_key(&qualified) || self.globals.contains_var(&qualified).await {
+                return Cow::Owned(qualified);
             }
-            None => {
-                eprintln!("Warning: find called without arguments");
-                base.to_string()
+        }
+        Cow::Borrowed(name)
+    }
+
+    /// Find a function, preferring the current module's own declaration
+    fn resolve_function(&self, name: &str) -> Option<(String, &'a Function)> {
+        let functions = self.functions;
+        if let Some(module) = &self.module {
+            let qualified = qualify(Some(module), name);
+            if let Some(function) = functions.get(&qualified) {
+                return Some((qualified, function));
             }
-        },
-        "trim" => base.trim().to_string(),
-        "rtrim" => base.trim_end().to_string(),
-        "ltrim" => base.trim_start().to_string(),
-        "repeat" => match arg {
-            Some(Expression::Number(n)) => base.repeat(*n as usize),
-            _ => base.repeat(2),
-        },
-        "repeat_sep" => match arg {
-            Some(Expression::Tuple(args_vec)) if args_vec.len() == 2 => {
-                let times_str = eval_expression(&args_vec[0], message, client, vars);
-                let add = eval_expression(&args_vec[1], message, client, vars);
+        }
+        functions.get(name).map(|function| (name.to_string(), function))
+    }
 
-                let times = times_str.parse::<usize>().unwrap_or(0);
+    /// Resolve a variable, checking the function scope, then connection
+    /// variables, then globals and constants
+    async fn lookup(&self, name: &str) -> Option<Value> {
+        if let Some(value) = self.locals.as_ref().and_then(|locals| locals.get(name)) {
+            return Some(value.clone());
+        }
+        if let Some(value) = self.vars.read().await.get(name) {
+            return Some(value.clone());
+        }
+        self.globals.get(&self.global_name(name).await).await
+    }
 
-                if times == 0 {
-                    base.to_string()
-                } else {
-                    std::iter::repeat(base)
-                        .take(times)
-                        .collect::<Vec<&str>>()
-                        .join(&add)
-                }
+    /// Whether `name` resolves to the global `global`: it is declared
+    /// globally and no connection variable, such as a regex capture, shadows it
+    async fn is_global_var(&self, name: &str, global: &str) -> bool {
+        !self.vars.read().await.contains_key(name) && self.globals.contains_var(global).await
+    }
+
+    /// Assign a variable in the function scope when inside a function,
+    /// otherwise on the connection. Names declared globally update the global.
+    async fn assign(&mut self, name: &str, value: Value) -> Result<(), RuntimeError> {
+        let global = self.global_name(name).await;
+        if self.globals.constants.contains_key(global.as_ref()) {
+            return Err(ErrorKind::Assignment(format!("cannot assign to constant '{}'", name)).into());
+        }
+
+        let is_global = self.is_global_var(name, &global).await;
+        match &mut self.locals {
+            Some(locals) if locals.contains_key(name) || !is_global => {
+                locals.insert(name.to_string(), value);
             }
-            Some(_) => {
-                eprintln!("Warning: repeat_sep requires 2 argument, got {:?}", arg);
-                base.to_string()
+            None if !is_global => {
+                self.vars.write().await.insert(name.to_string(), value);
             }
-            None => {
-                eprintln!("Warning: repeat_sep called without arguments");
-                base.to_string()
+            _ => {
+                self.globals.vars.write().await.insert(global.into_owned(), value);
             }
         }
-        "replace" => match arg {
-            Some(Expression::Tuple(args_vec)) if args_vec.len() == 2 => {
-                let from = eval_expression(&args_vec[0], message, client, vars);
-                let to = eval_expression(&args_vec[1], message, client, vars);
-                base.replace(&from, &to)
+        Ok(())
+    }
+
+    /// Bind a loop, pattern or capture variable in the innermost scope: the
+    /// function scope inside a function, otherwise the connection. Unlike
+    /// `assign` it never writes to a global, which the binding shadows instead.
+    /// Returns the value it replaced, for `unbind` once the binding's block ends.
+    async fn bind(&mut self, name: &str, value: Value) -> Result<Option<Value>, RuntimeError> {
+        if self.globals.constants.contains_key(self.global_name(name).await.as_ref()) {
+            return Err(ErrorKind::Assignment(format!("cannot assign to constant '{}'", name)).into());
+        }
+        Ok(match &mut self.locals {
+            Some(locals) => locals.insert(name.to_string(), value),
+            None => self.vars.write().await.insert(name.to_string(), value),
+        })
+    }
+
+    /// Undo a `bind`, restoring the value it replaced
+    async fn unbind(&mut self, name: &str, previous: Option<Value>) {
+        match (&mut self.locals, previous) {
+            (Some(locals), Some(previous)) => {
+                locals.insert(name.to_string(), previous);
             }
-            Some(_) => {
-                eprintln!("Warning: replace requires 2 arguments, got {:?}", arg);
-                base.to_string()
+            (Some(locals), None) => {
+                locals.remove(name);
             }
-            None => {
-                eprintln!("Warning: replace called without arguments");
-                base.to_string()
+            (None, Some(previous)) => {
+                self.vars.write().await.insert(name.to_string(), previous);
             }
-        },
-        "remove" => match arg {
-            Some(Expression::String(arg)) if arg != "" => {
-                base.replace(arg, "").to_string()
+            (None, None) => {
+                self.vars.write().await.remove(name);
             }
-            Some(_) => {
-                eprintln!("Warning: remove requires 1 arguments, got {:?}", arg);
-                base.to_string()
+        }
+    }
+
+    /// Undo several `bind`s, latest first
+    async fn unbind_all(&mut self, replaced: Vec<(String, Option<Value>)>) {
+        for (name, previous) in replaced.into_iter().rev() {
+            self.unbind(&name, previous).await;
+        }
+    }
+
+    /// Apply `op` to a variable in place: a function's local, otherwise the
+    /// connection variable or global it names. The scope stays locked from
+    /// the read to the write, so concurrent handlers updating the same
+    /// global can't lose each other's updates.
+    async fn update(&mut self, name: &str, op: &ArithmeticOperator, amount: Value) -> EvalResult {
+        let global = self.global_name(name).await;
+        if self.globals.constants.contains_key(global.as_ref()) {
+            return Err(ErrorKind::Assignment(format!("cannot assign to constant '{}'", name)).into());
+        }
+        let unset = || ErrorKind::Assignment(format!("cannot update '{}' before it is set", name));
+
+        let is_global = self.is_global_var(name, &global).await;
+        match &mut self.locals {
+            Some(locals) if locals.contains_key(name) => {
+                let updated = eval_arithmetic(locals[name].clone(), op, amount)?;
+                locals.insert(name.to_string(), updated.clone());
+                Ok(updated)
             }
-            None => {
-                eprintln!("Warning: remove called without arguments");
-                base.to_string()
+            // Inside a function this updates a connection variable in place,
+            // rather than a local copy the update would be lost with
+            _ if !is_global => {
+                let mut vars = self.vars.write().await;
+                let current = vars.get(name).cloned().ok_or_else(unset)?;
+                let updated = eval_arithmetic(current, op, amount)?;
+                vars.insert(name.to_string(), updated.clone());
+                Ok(updated)
             }
-        },
-        "count" => match arg {
-            Some(Expression::String(arg)) if arg != "" => {
-                base.matches(arg).count().to_string()
+            _ => {
+                let mut vars = self.globals.vars.write().await;
+                let current = vars.get(global.as_ref()).cloned().ok_or_else(unset)?;
+                let updated = eval_arithmetic(current, op, amount)?;
+                vars.insert(global.into_owned(), updated.clone());
+                Ok(updated)
             }
-            Some(_) => {
-                eprintln!("Warning: count requires 1 arguments, got {:?}", arg);
-                base.to_string()
+        }
+    }
+}
+
+fn declare_functions(statements: &[Statement], module: Option<&str>, functions: &mut Functions) {
+    for stmt in statements {
+        match &stmt.kind {
+            StatementKind::Function { name, params, body, .. } => {
+                let function = Function {
+                    params: params.clone(),
+                    body: body.clone(),
+                    module: module.map(str::to_string),
+                };
+                functions.insert(qualify(module, name), function);
             }
-            None => {
-                eprintln!("Warning: count called without arguments");
-                base.to_string()
+            StatementKind::Module { name, body } => declare_functions(body, Some(name), functions),
+            _ => {}
+        }
+    }
+}
+
+/// Build the function table from top-level declarations and the server body.
+/// Functions declared in the server take precedence over top-level ones.
+fn extract_functions(shared: &[Statement], body: &[Statement], module: Option<&str>) -> Functions {
+    let mut functions = HashMap::new();
+    declare_functions(shared, None, &mut functions);
+    declare_functions(body, module, &mut functions);
+    functions
+}
+
+/// Extract `On` events from the server body
+fn extract_events(body: &[Statement]) -> Vec<Statement> {
+    body.iter().filter_map(|stmt| {
+        if let StatementKind::On { .. } = &stmt.kind {
+            Some(stmt.clone())
+        } else { None }
+    }).collect()
+}
+
+/// Look `method` up in the registry, check its arguments and run it.
+/// `base` is the value of `receiver`.
+async fn apply_method(
+    base: &Value,
+    receiver: &Expression,
+    method: &str,
+    arg: Option<&Expression>,
+    ctx: &mut Context<'_>,
+) -> EvalResult {
+    let spec = methods::lookup(base, method)?;
+    // The replacement is evaluated once per match, so it is passed on unevaluated
+    if let (Implementation::ReplaceRe, Value::String(s)) = (&spec.implementation, base) {
+        return replace_re(s, spec, arg, ctx).await;
+    }
+
+    let args = eval_args(arg, ctx).await?;
+    spec.check_args(method, &args)?;
+    match (&spec.implementation, base) {
+        (Implementation::Any(apply), _) => apply(base, &args),
+        (Implementation::String(apply), Value::String(s)) => apply(s, &args),
+        (Implementation::List(apply), Value::List(items)) => apply(items, &args),
+        (Implementation::ListInPlace(apply), Value::List(items)) => {
+            let mut items = items.clone();
+            let result = apply(&mut items, &args)?;
+            if let ExpressionKind::Variable(name) = &receiver.kind {
+                ctx.assign(name, Value::List(items)).await?;
             }
-        },
-        "is_empty" => base.is_empty().to_string(),
-        "typeof" | "type_of" => {
-            if base.parse::<f64>().is_ok() {
-                "number".to_string()
-            } else if base.starts_with('(') && base.ends_with(')') {
-                "tuple".to_string()
-            } else if base == "true" || base == "false" {
-                "boolean".to_string()
-            } else if base.is_empty() {
-                "empty".to_string()
-            } else {
-                "string".to_string()
+            Ok(result)
+        }
+        (Implementation::Map(apply), Value::Map(entries)) => apply(entries, &args),
+        (Implementation::Captures, Value::String(s)) => {
+            let regex = to_regex(&args[0])?;
+            let groups = regex.captures(s).map(|caps| capture_groups(&regex, &caps));
+            let matches = method == "matches";
+            match groups {
+                Some(groups) => {
+                    let numbered = groups
+                        .iter()
+                        .filter(|(name, _)| name.parse::<usize>().is_ok())
+                        .map(|(_, value)| value.clone())
+                        .collect();
+                    bind_captures(groups, ctx).await?;
+                    Ok(if matches { Value::Boolean(true) } else { Value::List(numbered) })
+                }
+                None if matches => Ok(Value::Boolean(false)),
+                None => Ok(Value::Null),
             }
-        },
-        unknown => {
-            eprintln!("Warning: unknown method '{}'", unknown);
-            base.to_string()
         }
+        _ => Err(methods::unknown_method(base, method).into()),
+    }
+}
+
+/// `replace_re(pattern, replacement)`, evaluating the replacement once per
+/// match with that match's groups bound
+async fn replace_re(base: &str, spec: &Method, arg: Option<&Expression>, ctx: &mut Context<'_>) -> EvalResult {
+    let args = methods::call_args(arg);
+    let [pattern, replacement] = args[..] else {
+        return Err(argument_error("replace_re", spec.arity_message(args.len())));
+    };
+
+    let pattern = eval_expression(pattern, ctx).await?;
+    spec.check_args("replace_re", &[pattern.clone(), Value::Null])?;
+    let regex = to_regex(&pattern)?;
+    let found: Vec<_> = regex
+        .captures_iter(base)
+        .filter_map(|caps| {
+            let whole = caps.get(0)?;
+            Some((whole.start(), whole.end(), capture_groups(&regex, &caps)))
+        })
+        .collect();
+
+    let mut result = String::new();
+    let mut last = 0;
+    for (start, end, groups) in found {
+        result.push_str(&base[last..start]);
+        bind_captures(groups, ctx).await?;
+        result.push_str(&eval_expression(replacement, ctx).await?.to_string());
+        last = end;
     }
+    result.push_str(&base[last..]);
+    Ok(Value::String(result))
 }
 
-// Helper function to evaluate truthiness
-fn is_truthy(s: &str) -> bool {
-    s == "true" || (s != "false" && s != "0" && !s.is_empty())
+/// Accept a regex literal, or a pattern string compiled on the fly
+pub fn to_regex(value: &Value) -> Result<Regex, RuntimeError> {
+    match value {
+        Value::Regex(regex) => Ok(regex.clone()),
+        Value::String(pattern) => Regex::new(pattern)
+            .map_err(|e| type_error(format!("invalid regex '{}': {}", pattern, e))),
+        other => Err(type_error(format!("expected a regex, got {}", other.type_name()))),
+    }
 }
 
-/// Evaluate an expression to a string
-pub fn eval_expression(
-    expr: &Expression,
-    message: Option<&str>,
-    client: Option<&str>,
-    vars: &HashMap<String, String>,
-) -> String {
-    match expr {
-        Expression::String(s) => eval_template(s, message, client, vars),
-        Expression::Variable(v) => match v.as_str() {
-            "message" => message.unwrap_or("").to_string(),
-            "client" => client.unwrap_or("").to_string(),
-            _ => vars.get(v).cloned().unwrap_or_else(|| format!("${}", v)),
+/// Groups of a match as `(name, value)` pairs: `0`, `1`, ... plus any named groups
+fn capture_groups(regex: &Regex, caps: &regex::Captures<'_>) -> Vec<(String, Value)> {
+    let mut groups = Vec::new();
+    for (i, name) in regex.capture_names().enumerate() {
+        let value = caps.get(i).map(|m| Value::from(m.as_str())).unwrap_or(Value::Null);
+        if let Some(name) = name {
+            groups.push((name.to_string(), value.clone()));
+        }
+        groups.push((i.to_string(), value));
+    }
+    groups
+}
+
+/// Expose the groups of the latest match as `$0`, `$1`, ... and named variables
+async fn bind_captures(groups: Vec<(String, Value)>, ctx: &mut Context<'_>) -> Result<(), RuntimeError> {
+    for (name, value) in groups {
+        ctx.bind(&name, value).await?;
+    }
+    Ok(())
+}
+
+/// Evaluate method arguments, flattening the tuple built for multi-argument calls
+async fn eval_args(arg: Option<&Expression>, ctx: &mut Context<'_>) -> Result<Vec<Value>, RuntimeError> {
+    match arg {
+        Some(Expression { kind: ExpressionKind::Tuple(args_vec), .. }) => {
+            let mut args = Vec::with_capacity(args_vec.len());
+            for a in args_vec {
+                args.push(eval_expression(a, ctx).await?);
+            }
+            Ok(args)
+        }
+        Some(a) => Ok(vec![eval_expression(a, ctx).await?]),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Resolve an index into a sequence of `len` items; negative indices count from the end
+fn resolve_index(n: i64, len: usize) -> Option<usize> {
+    let position = if n < 0 { n.checked_add(len as i64)? } else { n };
+    usize::try_from(position).ok().filter(|position| *position < len)
+}
+
+fn eval_index(object: &Value, index: &Value) -> EvalResult {
+    match (object, index) {
+        (Value::List(items), Value::Integer(n)) => {
+            let item = resolve_index(*n, items.len()).map(|n| &items[n]);
+            item.cloned().ok_or_else(|| {
+                let message = format!("index {} out of range for list of length {}", n, items.len());
+                ErrorKind::Index(message).into()
+            })
+        }
+        (Value::List(_), other) => {
+            Err(type_error(format!("list index must be an integer, got {}", other.type_name())))
+        }
+        // Strings index by character, not byte
+        (Value::String(s), Value::Integer(n)) => {
+            let len = s.chars().count();
+            let c = resolve_index(*n, len).and_then(|n| s.chars().nth(n));
+            c.map(|c| Value::String(c.to_string())).ok_or_else(|| {
+                let message = format!("index {} out of range for string of length {}", n, len);
+                ErrorKind::Index(message).into()
+            })
+        }
+        (Value::String(_), other) => {
+            Err(type_error(format!("string index must be an integer, got {}", other.type_name())))
+        }
+        (Value::Map(entries), Value::String(key)) => entries
+            .get(key)
+            .cloned()
+            .ok_or_else(|| ErrorKind::Index(format!("key '{}' not found in map", key)).into()),
+        (Value::Map(_), other) => {
+            Err(type_error(format!("map key must be a string, got {}", other.type_name())))
+        }
+        (other, _) => Err(type_error(format!("cannot index into {}", other.type_name()))),
+    }
+}
+
+/// Slice a string by characters or a list by items. Negative bounds count
+/// from the end and bounds past either end are clamped, so `s[..10]` is
+/// at most ten characters.
+fn eval_slice(object: &Value, start: Option<Value>, end: Option<Value>) -> EvalResult {
+    let bound = |value: Option<Value>| match value {
+        None => Ok(None),
+        Some(Value::Integer(n)) => Ok(Some(n)),
+        Some(other) => Err(type_error(format!("slice bounds must be integers, got {}", other.type_name()))),
+    };
+    let (start, end) = (bound(start)?, bound(end)?);
+    let range = |len: usize| {
+        let clamp = |n: i64| {
+            let position = if n < 0 { n.saturating_add(len as i64) } else { n };
+            position.clamp(0, len as i64) as usize
+        };
+        let start = start.map_or(0, clamp);
+        (start, end.map_or(len, clamp).max(start))
+    };
+
+    match object {
+        Value::String(s) => {
+            let (start, end) = range(s.chars().count());
+            Ok(Value::String(s.chars().skip(start).take(end - start).collect()))
+        }
+        Value::List(items) => {
+            let (start, end) = range(items.len());
+            Ok(Value::List(items[start..end].to_vec()))
+        }
+        other => Err(type_error(format!("cannot slice {}", other.type_name()))),
+    }
+}
+
+/// Order two values of compatible types; `None` when they can't be compared
+pub fn compare_values(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Integer(l), Value::Integer(r)) => Some(l.cmp(r)),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Boolean(l), Value::Boolean(r)) => Some(l.cmp(r)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        (Value::List(l), Value::List(r)) => {
+            let equal = l.len() == r.len()
+                && l.iter().zip(r).all(|(a, b)| compare_values(a, b) == Some(Ordering::Equal));
+            equal.then_some(Ordering::Equal)
+        }
+        (Value::Regex(l), Value::Regex(r)) => (l.as_str() == r.as_str()).then_some(Ordering::Equal),
+        (Value::Map(l), Value::Map(r)) => {
+            let equal = l.len() == r.len()
+                && l.iter().zip(r).all(|((lk, lv), (rk, rv))| {
+                    lk == rk && compare_values(lv, rv) == Some(Ordering::Equal)
+                });
+            equal.then_some(Ordering::Equal)
+        }
+        _ => match (left.as_f64(), right.as_f64()) {
+            (Some(l), Some(r)) => l.partial_cmp(&r),
+            _ => None,
         },
-        Expression::Number(n) => n.to_string(),
-        Expression::MethodCall { object, method, arg } => {
-            let base = eval_expression(object, message, client, vars);
-            apply_method(&base, method, arg.as_deref(), message, client, vars)
-        }
-        Expression::BinaryOp { left, op, right } => {
-            let left_val = eval_expression(left, message, client, vars);
-            let right_val = eval_expression(right, message, client, vars);
-
-            // Try to parse as numbers for comparison
-            let left_num = left_val.parse::<f64>();
-            let right_num = right_val.parse::<f64>();
-
-            let result = match (left_num, right_num) {
-                (Ok(l), Ok(r)) => {
-                    // Numeric comparison
-                    match op {
-                        BinaryOperator::Equal => l == r,
-                        BinaryOperator::NotEqual => l != r,
-                        BinaryOperator::GreaterThan => l > r,
-                        BinaryOperator::LessThan => l < r,
-                        BinaryOperator::GreaterEqual => l >= r,
-                        BinaryOperator::LessEqual => l <= r,
-                    }
+    }
+}
+
+fn eval_comparison(left: &Value, op: &BinaryOperator, right: &Value) -> Result<bool, RuntimeError> {
+    let ordering = compare_values(left, right);
+
+    match op {
+        BinaryOperator::Equal => Ok(ordering == Some(Ordering::Equal)),
+        BinaryOperator::NotEqual => Ok(ordering != Some(Ordering::Equal)),
+        _ => {
+            let Some(ordering) = ordering else {
+                return Err(type_error(format!(
+                    "cannot compare {} with {}",
+                    left.type_name(),
+                    right.type_name()
+                )));
+            };
+            Ok(match op {
+                BinaryOperator::GreaterThan => ordering == Ordering::Greater,
+                BinaryOperator::LessThan => ordering == Ordering::Less,
+                BinaryOperator::GreaterEqual => ordering != Ordering::Less,
+                BinaryOperator::LessEqual => ordering != Ordering::Greater,
+                BinaryOperator::Equal | BinaryOperator::NotEqual => unreachable!(),
+            })
+        }
+    }
+}
+
+fn eval_arithmetic(left: Value, op: &ArithmeticOperator, right: Value) -> EvalResult {
+    match (&left, &right) {
+        (Value::Integer(l), Value::Integer(r)) => {
+            let (l, r) = (*l, *r);
+            let result = match op {
+                ArithmeticOperator::Add => l.checked_add(r),
+                ArithmeticOperator::Subtract => l.checked_sub(r),
+                ArithmeticOperator::Multiply => l.checked_mul(r),
+                ArithmeticOperator::Divide | ArithmeticOperator::Modulo if r == 0 => {
+                    return Err(ErrorKind::DivisionByZero.into());
                 }
-                _ => {
-                    // String comparison
-                    match op {
-                        BinaryOperator::Equal => left_val == right_val,
-                        BinaryOperator::NotEqual => left_val != right_val,
-                        BinaryOperator::GreaterThan => left_val > right_val,
-                        BinaryOperator::LessThan => left_val < right_val,
-                        BinaryOperator::GreaterEqual => left_val >= right_val,
-                        BinaryOperator::LessEqual => left_val <= right_val,
-                    }
+                // Only stay integral when the division is exact. `MIN / -1`
+                // has no remainder but overflows, which `checked_div` reports.
+                ArithmeticOperator::Divide if l.checked_rem(r).is_some_and(|rem| rem != 0) => {
+                    return Ok(Value::Float(l as f64 / r as f64));
                 }
+                ArithmeticOperator::Divide => l.checked_div(r),
+                ArithmeticOperator::Modulo => l.checked_rem(r),
             };
+            result
+                .map(Value::Integer)
+                .ok_or_else(|| ErrorKind::Overflow(format!("{} {} {}", l, op, r)).into())
+        }
+        // `+` joins two strings, but never a string and another type
+        (Value::String(l), Value::String(r)) if *op == ArithmeticOperator::Add => {
+            Ok(Value::String(format!("{}{}", l, r)))
+        }
+        (Value::String(_), _) | (_, Value::String(_)) if *op == ArithmeticOperator::Add => Err(type_error(format!(
+            "cannot add {} and {}; use '~' to concatenate",
+            left.type_name(),
+            right.type_name()
+        ))),
+        _ => match (left.as_f64(), right.as_f64()) {
+            (Some(l), Some(r)) => match op {
+                ArithmeticOperator::Add => Ok(Value::Float(l + r)),
+                ArithmeticOperator::Subtract => Ok(Value::Float(l - r)),
+                ArithmeticOperator::Multiply => Ok(Value::Float(l * r)),
+                ArithmeticOperator::Divide | ArithmeticOperator::Modulo if r == 0.0 => {
+                    Err(ErrorKind::DivisionByZero.into())
+                }
+                ArithmeticOperator::Divide => Ok(Value::Float(l / r)),
+                ArithmeticOperator::Modulo => Ok(Value::Float(l % r)),
+            },
+            _ => Err(type_error(format!(
+                "cannot apply '{}' to {} and {}",
+                op,
+                left.type_name(),
+                right.type_name()
+            ))),
+        },
+    }
+}
+
+/// Evaluate an expression to a value
+pub fn eval_expression<'a>(expr: &'a Expression, ctx: &'a mut Context<'_>) -> BoxFuture<'a, EvalResult> {
+    Box::pin(async move {
+        evaluate(expr, ctx).await.map_err(|mut error| {
+            error.span.get_or_insert_with(|| expr.span.clone());
+            error
+        })
+    })
+}
 
-            result.to_string()
+async fn evaluate(expr: &Expression, ctx: &mut Context<'_>) -> EvalResult {
+    let value = match &expr.kind {
+        ExpressionKind::String(s) => Value::String(s.clone()),
+        ExpressionKind::Template(parts) => Value::String(eval_template(parts, ctx).await?),
+        ExpressionKind::Variable(v) => match v.as_str() {
+            "message" => Value::from(ctx.message.unwrap_or("")),
+            "client" => ctx.client.map(|c| Value::Integer(c.into())).unwrap_or(Value::Null),
+            "error" if ctx.error.is_some() => Value::from(ctx.error.as_deref().unwrap_or("")),
+            // Unset variables are null; `is_set` tells them apart from ones set to null
+            _ => ctx.lookup(v).await.unwrap_or(Value::Null),
+        },
+        ExpressionKind::Integer(n) => Value::Integer(*n),
+        ExpressionKind::Float(n) => Value::Float(*n),
+        ExpressionKind::Boolean(b) => Value::Boolean(*b),
+        ExpressionKind::Null => Value::Null,
+        ExpressionKind::IsSet(name) => Value::Boolean(match name.as_str() {
+            "message" => ctx.message.is_some(),
+            "client" => ctx.client.is_some(),
+            "error" if ctx.error.is_some() => true,
+            _ => ctx.lookup(name).await.is_some(),
+        }),
+        ExpressionKind::Conditional { condition, then_value, else_value } => {
+            if eval_expression(condition, ctx).await?.is_truthy() {
+                eval_expression(then_value, ctx).await?
+            } else {
+                eval_expression(else_value, ctx).await?
+            }
+        }
+        ExpressionKind::Default { value, default } => match eval_expression(value, ctx).await? {
+            Value::Null => eval_expression(default, ctx).await?,
+            value => value,
+        },
+        ExpressionKind::Regex(regex) => Value::Regex(regex.clone()),
+        ExpressionKind::MethodCall { object, method, arg } => {
+            if let Some(function) = module_member(object, method, ctx).await {
+                if ctx.resolve_function(&function).is_some() {
+                    let args = eval_args(arg.as_deref(), ctx).await?;
+                    return call_function(&function, args, ctx).await;
+                }
+            }
+            let base = eval_expression(object, ctx).await?;
+            apply_method(&base, object, method, arg.as_deref(), ctx).await?
+        }
+        ExpressionKind::BinaryOp { left, op, right } => {
+            let left_val = eval_expression(left, ctx).await?;
+            let right_val = eval_expression(right, ctx).await?;
+            Value::Boolean(eval_comparison(&left_val, op, &right_val)?)
         }
-        Expression::LogicalOp { left, op, right } => {
-            let left_val = eval_expression(left, message, client, vars);
-            let left_bool = is_truthy(&left_val);
+        ExpressionKind::LogicalOp { left, op, right } => {
+            let left_bool = eval_expression(left, ctx).await?.is_truthy();
 
             // Short-circuit evaluation
             let result = match op {
                 LogicalOperator::And => {
-                    if !left_bool {
-                        false
-                    } else {
-                        let right_val = eval_expression(right, message, client, vars);
-                        is_truthy(&right_val)
-                    }
+                    left_bool && eval_expression(right, ctx).await?.is_truthy()
                 }
                 LogicalOperator::Or => {
-                    if left_bool {
-                        true
-                    } else {
-                        let right_val = eval_expression(right, message, client, vars);
-                        is_truthy(&right_val)
-                    }
+                    left_bool || eval_expression(right, ctx).await?.is_truthy()
                 }
             };
 
-            result.to_string()
+            Value::Boolean(result)
         }
-        Expression::UnaryOp { op, operand } => {
-            let val = eval_expression(operand, message, client, vars);
-            match op {
-                UnaryOperator::Not => (!is_truthy(&val)).to_string(),
+        ExpressionKind::UnaryOp { op, operand } => {
+            let val = eval_expression(operand, ctx).await?;
+            match (op, val) {
+                (UnaryOperator::Not, val) => Value::Boolean(!val.is_truthy()),
+                (UnaryOperator::Negate, Value::Integer(n)) => match n.checked_neg() {
+                    Some(n) => Value::Integer(n),
+                    None => return Err(ErrorKind::Overflow(format!("-({})", n)).into()),
+                },
+                (UnaryOperator::Negate, Value::Float(n)) => Value::Float(-n),
+                (UnaryOperator::Negate, other) => {
+                    return Err(type_error(format!("cannot negate {}", other.type_name())));
+                }
+                (UnaryOperator::Plus, val @ (Value::Integer(_) | Value::Float(_))) => val,
+                (UnaryOperator::Plus, other) => {
+                    return Err(type_error(format!("cannot apply unary '+' to {}", other.type_name())));
+                }
             }
         }
-        Expression::Concat { left, right } => {
-            let left_val = eval_expression(left, message, client, vars);
-            let right_val = eval_expression(right, message, client, vars);
-            format!("{}{}", left_val, right_val)
+        ExpressionKind::Concat { left, right } => {
+            let left_val = eval_expression(left, ctx).await?;
+            let right_val = eval_expression(right, ctx).await?;
+            Value::String(format!("{}{}", left_val, right_val))
         }
-        Expression::Arithmetic { left, op, right } => {
-            let left_val = eval_expression(left, message, client, vars);
-            let right_val = eval_expression(right, message, client, vars);
-
-            match op {
-                ArithmeticOperator::Add => {
-                    // Try numeric addition first
-                    if let (Ok(l), Ok(r)) = (left_val.parse::<f64>(), right_val.parse::<f64>()) {
-                        let result = l + r;
-                        if result.fract() == 0.0 {
-                            format!("{}", result as i64)
-                        } else {
-                            format!("{}", result)
-                        }
-                    } else {
-                        // Fallback to string concatenation
-                        format!("{}{}", left_val, right_val)
-                    }
-                }
-                ArithmeticOperator::Subtract => {
-                    if let (Ok(l), Ok(r)) = (left_val.parse::<f64>(), right_val.parse::<f64>()) {
-                        format!("{}", l - r)
-                    } else {
-                        "NaN".into()
-                    }
-                }
-                ArithmeticOperator::Multiply => {
-                    if let (Ok(l), Ok(r)) = (left_val.parse::<f64>(), right_val.parse::<f64>()) {
-                        format!("{}", l * r)
-                    } else {
-                        "NaN".into()
-                    }
+        ExpressionKind::Arithmetic { left, op, right } => {
+            let left_val = eval_expression(left, ctx).await?;
+            let right_val = eval_expression(right, ctx).await?;
+            eval_arithmetic(left_val, op, right_val)?
+        }
+        ExpressionKind::List(items) => {
+            let mut values = Vec::with_capacity(items.len());
+            for item in items {
+                values.push(eval_expression(item, ctx).await?);
+            }
+            Value::List(values)
+        }
+        ExpressionKind::Map(entries) => {
+            let mut map = BTreeMap::new();
+            for (key, value) in entries {
+                let key = eval_expression(key, ctx).await?.to_string();
+                map.insert(key, eval_expression(value, ctx).await?);
+            }
+            Value::Map(map)
+        }
+        ExpressionKind::Field { object, name } => {
+            if let Some(member) = module_member(object, name, ctx).await {
+                if let Some(value) = ctx.lookup(&member).await {
+                    return Ok(value);
                 }
-                ArithmeticOperator::Divide => {
-                    if let (Ok(l), Ok(r)) = (left_val.parse::<f64>(), right_val.parse::<f64>()) {
-                        if r == 0.0 {
-                            eprintln!("Warning: division by zero");
-                            "NaN".into()
-                        } else {
-                            format!("{}", l / r)
-                        }
-                    } else {
-                        "NaN".into()
-                    }
+            }
+            match eval_expression(object, ctx).await? {
+                map @ Value::Map(_) => eval_index(&map, &Value::from(name.as_str()))?,
+                // Anything else treats `.name` as a method call without arguments
+                other => apply_method(&other, object, name, None, ctx).await?,
+            }
+        }
+        ExpressionKind::Range { start, end } => {
+            let start_val = eval_expression(start, ctx).await?;
+            let end_val = eval_expression(end, ctx).await?;
+            match (start_val, end_val) {
+                (Value::Integer(start), Value::Integer(end))
+                    if end.saturating_sub(start) <= MAX_LOOP_ITERATIONS as i64 =>
+                {
+                    Value::List((start..end).map(Value::Integer).collect())
                 }
-                ArithmeticOperator::Modulo => {
-                    if let (Ok(l), Ok(r)) = (left_val.parse::<f64>(), right_val.parse::<f64>()) {
-                        if r == 0.0 {
-                            eprintln!("Warning: modulo by zero");
-                            "NaN".into()
-                        } else {
-                            format!("{}", l % r)
-                        }
-                    } else {
-                        "NaN".into()
-                    }
+                (Value::Integer(start), Value::Integer(end)) => {
+                    return Err(ErrorKind::Limit(format!("range {}..{} is too large", start, end)).into());
                 }
+                (start, end) => return Err(range_bounds_error(&start, &end)),
+            }
+        }
+        ExpressionKind::Index { object, index } => {
+            let object_val = eval_expression(object, ctx).await?;
+            let index_val = eval_expression(index, ctx).await?;
+            eval_index(&object_val, &index_val)?
+        }
+        ExpressionKind::Slice { object, start, end } => {
+            let object_val = eval_expression(object, ctx).await?;
+            let start_val = match start {
+                Some(start) => Some(eval_expression(start, ctx).await?),
+                None => None,
+            };
+            let end_val = match end {
+                Some(end) => Some(eval_expression(end, ctx).await?),
+                None => None,
+            };
+            eval_slice(&object_val, start_val, end_val)?
+        }
+        ExpressionKind::Call { name, args } => {
+            let mut values = Vec::with_capacity(args.len());
+            for arg in args {
+                values.push(eval_expression(arg, ctx).await?);
+            }
+            call_function(name, values, ctx).await?
+        }
+        ExpressionKind::Tuple(_) => {
+            return Err(type_error("unexpected tuple expression"));
+        }
+    };
+    Ok(value)
+}
+
+fn range_bounds_error(start: &Value, end: &Value) -> RuntimeError {
+    type_error(format!(
+        "range bounds must be integers, got {} and {}",
+        start.type_name(),
+        end.type_name()
+    ))
+}
+
+/// `module.member` when `object` names an imported module rather than a variable
+async fn module_member(object: &Expression, member: &str, ctx: &Context<'_>) -> Option<String> {
+    match &object.kind {
+        ExpressionKind::Variable(module) if ctx.lookup(module).await.is_none() => {
+            Some(format!("{}.{}", module, member))
+        }
+        _ => None,
+    }
+}
+
+/// Run a user-defined function in its own local scope and return its result
+async fn call_function(name: &str, args: Vec<Value>, ctx: &mut Context<'_>) -> EvalResult {
+    let Some((name, function)) = ctx.resolve_function(name) else {
+        return Err(ErrorKind::UnknownFunction(name.to_string()).into());
+    };
+
+    if args.len() != function.params.len() {
+        let message = format!("expects {} argument(s), got {}", function.params.len(), args.len());
+        return Err(argument_error(&name, message));
+    }
+    if ctx.depth >= MAX_CALL_DEPTH {
+        let message = format!("maximum call depth exceeded calling '{}'", name);
+        return Err(ErrorKind::Limit(message).into());
+    }
+
+    let locals = function.params.iter().cloned().zip(args).collect();
+
+    let saved = ctx.locals.replace(locals);
+    let saved_module = std::mem::replace(&mut ctx.module, function.module.clone());
+    ctx.depth += 1;
+    let result = execute_statements(&function.body, ctx).await;
+    ctx.depth -= 1;
+    ctx.locals = saved;
+    ctx.module = saved_module;
+
+    match result? {
+        Flow::Return(value) => Ok(value),
+        Flow::Normal | Flow::Break | Flow::Continue => Ok(Value::Null),
+    }
+}
+
+/// Store `value` at `keys` inside `target`, walking nested maps and lists
+fn assign_path(target: &mut Value, keys: &[Value], value: Value) -> Result<(), RuntimeError> {
+    let Some((key, rest)) = keys.split_first() else {
+        *target = value;
+        return Ok(());
+    };
+
+    let slot = match (target, key) {
+        (Value::Map(entries), Value::String(k)) => {
+            if rest.is_empty() {
+                entries.insert(k.clone(), value);
+                return Ok(());
             }
+            entries
+                .get_mut(k)
+                .ok_or_else(|| ErrorKind::Index(format!("key '{}' not found in map", k)))?
+        }
+        (Value::List(items), Value::Integer(n)) => {
+            let len = items.len();
+            usize::try_from(*n).ok().and_then(|n| items.get_mut(n)).ok_or_else(|| {
+                ErrorKind::Index(format!("index {} out of range for list of length {}", n, len))
+            })?
+        }
+        (target, key) => {
+            return Err(type_error(format!(
+                "cannot assign into {} with {} key",
+                target.type_name(),
+                key.type_name()
+            )));
         }
-        Expression::Tuple(_) => {
-            eprintln!("Warning: unexpected tuple expression at top level");
-            "".to_string()
+    };
+
+    assign_path(slot, rest, value)
+}
+
+/// Test `value` against a match pattern, collecting any variables it binds
+fn match_pattern(pattern: &Pattern, value: &Value, bindings: &mut Vec<(String, Value)>) -> bool {
+    match pattern {
+        Pattern::Wildcard => true,
+        Pattern::Binding(name) => {
+            bindings.push((name.clone(), value.clone()));
+            true
         }
+        Pattern::Literal(literal) => compare_values(literal, value) == Some(Ordering::Equal),
+        Pattern::Prefix { prefix, rest } => match value {
+            Value::String(s) => match s.strip_prefix(prefix.as_str()) {
+                Some(remainder) => {
+                    if let Some(rest) = rest {
+                        bindings.push((rest.clone(), Value::from(remainder)));
+                    }
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        },
+        Pattern::Alternatives(alternatives) => alternatives
+            .iter()
+            .any(|alternative| match_pattern(alternative, value, bindings)),
     }
 }
 
-/// Execute statements for a single event
+/// Execute statements for a single event. An error skips the failing
+/// statement with a warning, unless running strict or inside `try`, in
+/// which case it propagates.
 fn execute_statements<'a>(
     statements: &'a [Statement],
-    socket: &'a mut tokio::net::TcpStream,
-    addr: &'a std::net::SocketAddr,
-    message: Option<&'a str>,
-    client: Option<&'a str>,
-    vars: Variables,
-) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Box<dyn std::error::Error>>> + Send + 'a>> {
+    ctx: &'a mut Context<'_>,
+) -> HandlerResult<'a> {
     Box::pin(async move {
         for stmt in statements {
-            match stmt {
-                Statement::SetVar { name, value } => {
-                    let vars_read = vars.read().await;
-                    let evaluated = eval_expression(value, message, client, &vars_read);
-                    drop(vars_read);
+            match execute_statement(stmt, ctx).await {
+                Ok(Flow::Normal) => {}
+                Ok(flow) => return Ok(flow),
+                Err(error) => {
+                    let error = error.escaping(stmt);
+                    if ctx.strict || ctx.catching > 0 {
+                        return Err(error);
+                    }
+                    report_error(&ctx.origin, "Warning", &error);
+                }
+            }
+        }
+        Ok(Flow::Normal)
+    })
+}
 
-                    let mut vars_write = vars.write().await;
-                    vars_write.insert(name.clone(), evaluated.clone());
-                    drop(vars_write);
+fn execute_statement<'a>(stmt: &'a Statement, ctx: &'a mut Context<'_>) -> HandlerResult<'a> {
+    Box::pin(async move {
+        match &stmt.kind {
+            StatementKind::SetVar { name, value } => {
+                let evaluated = eval_expression(value, ctx).await?;
+                ctx.assign(name, evaluated.clone()).await?;
+                println!("[{}] SET: {} = {}", ctx.origin, name, evaluated);
+            }
+            StatementKind::Update { name, op, value } => {
+                let amount = eval_expression(value, ctx).await?;
+                let updated = ctx.update(name, op, amount).await?;
+                println!("[{}] SET: {} = {}", ctx.origin, name, updated);
+            }
+            StatementKind::SetIndex { name, path, value } => {
+                let evaluated = eval_expression(value, ctx).await?;
+                let mut keys = Vec::with_capacity(path.len());
+                for key in path {
+                    keys.push(eval_expression(key, ctx).await?);
+                }
 
-                    println!("[{}] SET: {} = {}", addr, name, evaluated);
+                let global = ctx.global_name(name).await;
+                if ctx.globals.constants.contains_key(global.as_ref()) {
+                    let message = format!("cannot assign into constant '{}'", name);
+                    return Err(ErrorKind::Assignment(message).into());
+                } else if let Some(target) = ctx.locals.as_mut().and_then(|locals| locals.get_mut(name)) {
+                    assign_path(target, &keys, evaluated.clone())?;
+                } else {
+                    let mut vars_write = ctx.vars.write().await;
+                    match vars_write.get_mut(name) {
+                        Some(target) => assign_path(target, &keys, evaluated.clone())?,
+                        None => {
+                            drop(vars_write);
+                            let mut globals_write = ctx.globals.vars.write().await;
+                            let Some(target) = globals_write.get_mut(global.as_ref()) else {
+                                let message = format!("cannot assign into undefined variable '{}'", name);
+                                return Err(ErrorKind::Assignment(message).into());
+                            };
+                            assign_path(target, &keys, evaluated.clone())?;
+                        }
+                    }
                 }
-                Statement::If { condition, then_body, else_ifs, else_body } => {
-                    let vars_read = vars.read().await;
-                    let condition_result = eval_expression(condition, message, client, &vars_read);
-                    drop(vars_read);
 
-                    let is_true = is_truthy(&condition_result);
+                let path_str: String = keys.iter().map(|k| format!("[{}]", k)).collect();
+                println!("[{}] SET: {}{} = {}", ctx.origin, name, path_str, evaluated);
+            }
+            StatementKind::If { condition, then_body, else_ifs, else_body } => {
+                let mut branch = None;
 
-                    if is_true {
-                        execute_statements(then_body, socket, addr, message, client, vars.clone()).await?;
-                    } else {
-                        // Check else if conditions
-                        let mut executed = false;
-                        for (else_if_cond, else_if_body) in else_ifs {
-                            let vars_read = vars.read().await;
-                            let else_if_result = eval_expression(else_if_cond, message, client, &vars_read);
-                            drop(vars_read);
-
-                            if is_truthy(&else_if_result) {
-                                execute_statements(else_if_body, socket, addr, message, client, vars.clone()).await?;
-                                executed = true;
-                                break;
-                            }
+                if eval_expression(condition, ctx).await?.is_truthy() {
+                    branch = Some(then_body);
+                } else {
+                    // Check else if conditions
+                    for (else_if_cond, else_if_body) in else_ifs {
+                        if eval_expression(else_if_cond, ctx).await?.is_truthy() {
+                            branch = Some(else_if_body);
+                            break;
                         }
+                    }
+
+                    // If no else if matched, execute else block
+                    if branch.is_none() {
+                        branch = else_body.as_ref();
+                    }
+                }
 
-                        // If no else if matched, execute else block
-                        if !executed {
-                            if let Some(else_stmts) = else_body {
-                                execute_statements(else_stmts, socket, addr, message, client, vars.clone()).await?;
+                if let Some(body) = branch {
+                    return execute_statements(body, ctx).await;
+                }
+            }
+            StatementKind::While { condition, body } => {
+                let mut guard = LoopGuard::new();
+                while eval_expression(condition, ctx).await?.is_truthy() {
+                    guard.tick().await?;
+                    match execute_statements(body, ctx).await? {
+                        Flow::Break => break,
+                        Flow::Normal | Flow::Continue => {}
+                        flow @ Flow::Return(_) => return Ok(flow),
+                    }
+                }
+            }
+            StatementKind::For { var, iterable, body } => {
+                let items: Box<dyn Iterator<Item = Value> + Send> = match &iterable.kind {
+                    // Iterate ranges lazily instead of building the whole list
+                    ExpressionKind::Range { start, end } => {
+                        match (eval_expression(start, ctx).await?, eval_expression(end, ctx).await?) {
+                            (Value::Integer(start), Value::Integer(end)) => {
+                                Box::new((start..end).map(Value::Integer))
                             }
+                            (start, end) => return Err(range_bounds_error(&start, &end)),
+                        }
+                    }
+                    _ => match eval_expression(iterable, ctx).await? {
+                        Value::List(items) => Box::new(items.into_iter()),
+                        Value::Map(entries) => Box::new(entries.into_keys().map(Value::String)),
+                        Value::String(s) => Box::new(
+                            s.chars().map(|c| Value::String(c.to_string())).collect::<Vec<_>>().into_iter(),
+                        ),
+                        other => {
+                            return Err(type_error(format!("cannot iterate over {}", other.type_name())));
+                        }
+                    },
+                };
+
+                // The loop variable only lives for the loop, however it ends
+                let mut replaced = None;
+                let result: Result<Flow, RuntimeError> = async {
+                    let mut guard = LoopGuard::new();
+                    for item in items {
+                        guard.tick().await?;
+                        let previous = ctx.bind(var, item).await?;
+                        replaced.get_or_insert(previous);
+                        match execute_statements(body, ctx).await? {
+                            Flow::Break => break,
+                            Flow::Normal | Flow::Continue => {}
+                            flow @ Flow::Return(_) => return Ok(flow),
                         }
                     }
+                    Ok(Flow::Normal)
                 }
-                Statement::Log(expr) => {
-                    let vars_read = vars.read().await;
-                    let output = eval_expression(expr, message, client, &vars_read);
-                    drop(vars_read);
-                    println!("[{}] LOG: {}", addr, output);
+                .await;
+                if let Some(previous) = replaced {
+                    ctx.unbind(var, previous).await;
                 }
-                Statement::Send(expr) => {
-                    let vars_read = vars.read().await;
-                    let output = eval_expression(expr, message, client, &vars_read);
-                    drop(vars_read);
-                    let msg_with_newline = format!("{}\n", output);
-                    socket.write_all(msg_with_newline.as_bytes()).await?;
-                    socket.flush().await?;
-                    println!("[{}] SENT: {}", addr, output);
+                if let flow @ Flow::Return(_) = result? {
+                    return Ok(flow);
                 }
-                _ => {}
             }
+            StatementKind::Match { subject, arms } => {
+                let value = eval_expression(subject, ctx).await?;
+
+                for arm in arms {
+                    let mut bindings = Vec::new();
+                    if !match_pattern(&arm.pattern, &value, &mut bindings) {
+                        continue;
+                    }
+                    let mut replaced = Vec::with_capacity(bindings.len());
+                    for (name, bound) in bindings {
+                        let previous = ctx.bind(&name, bound).await?;
+                        replaced.push((name, previous));
+                    }
+                    // The guard sees the bindings, but they only stay for the arm's body
+                    if let Some(guard) = &arm.guard {
+                        let passed = eval_expression(guard, ctx).await.map(|value| value.is_truthy());
+                        if !matches!(passed, Ok(true)) {
+                            ctx.unbind_all(replaced).await;
+                            passed?;
+                            continue;
+                        }
+                    }
+
+                    let result = execute_statements(&arm.body, ctx).await;
+                    ctx.unbind_all(replaced).await;
+                    return result;
+                }
+            }
+            StatementKind::Try { body, catch_var, catch_body } => {
+                ctx.catching += 1;
+                let result = execute_statements(body, ctx).await;
+                ctx.catching -= 1;
+
+                return match result {
+                    Ok(flow) => Ok(flow),
+                    Err(error) => {
+                        let Some(var) = catch_var else {
+                            return execute_statements(catch_body, ctx).await;
+                        };
+                        let previous = ctx.bind(var, Value::String(error.kind.to_string())).await?;
+                        let result = execute_statements(catch_body, ctx).await;
+                        ctx.unbind(var, previous).await;
+                        result
+                    }
+                };
+            }
+            StatementKind::Break => return Ok(Flow::Break),
+            StatementKind::Continue => return Ok(Flow::Continue),
+            StatementKind::Log(expr) => {
+                let output = eval_expression(expr, ctx).await?;
+                println!("[{}] LOG {}: {}", ctx.origin, stmt.span, output);
+            }
+            StatementKind::Send(expr) => {
+                let output = eval_expression(expr, ctx).await?;
+                let Some(socket) = ctx.socket.as_mut() else {
+                    return Err(ErrorKind::Io("no client outside a connection".to_string()).into());
+                };
+                let msg_with_newline = format!("{}\n", output);
+                let written = async {
+                    socket.write_all(msg_with_newline.as_bytes()).await?;
+                    socket.flush().await
+                };
+                written.await.map_err(|e| ErrorKind::Io(e.to_string()))?;
+                println!("[{}] SENT: {}", ctx.origin, output);
+            }
+            StatementKind::Expression(expr) => {
+                eval_expression(expr, ctx).await?;
+            }
+            StatementKind::Return(expr) => {
+                let value = match expr {
+                    Some(expr) => eval_expression(expr, ctx).await?,
+                    None => Value::Null,
+                };
+                return Ok(Flow::Return(value));
+            }
+            _ => {}
         }
-        Ok(())
+        Ok(Flow::Normal)
     })
 }
 
-/// Trigger all events of a certain type
-async fn trigger_event(
-    events: &[Statement],
-    event_name: &str,
-    socket: &mut tokio::net::TcpStream,
-    addr: &std::net::SocketAddr,
-    message: Option<&str>,
-    client: Option<&str>,
-    vars: Variables,
-) {
+/// Run the handlers for `event_name`. An error escaping a handler is passed
+/// to the server's `on error` handlers as `$error`, or reported.
+async fn trigger_event(events: &[Statement], event_name: &str, ctx: &mut Context<'_>) {
     for stmt in events {
-        if let Statement::On { event, body } = stmt {
+        if let StatementKind::On { event, body, .. } = &stmt.kind {
             if event == event_name {
-                if let Err(e) = execute_statements(body, socket, addr, message, client, vars.clone()).await {
-                    eprintln!("[{}] Error executing '{}' handler: {}", addr, event_name, e);
+                let Err(error) = execute_statements(body, ctx).await else {
+                    continue;
+                };
+
+                let has_error_handler = events
+                    .iter()
+                    .any(|stmt| matches!(&stmt.kind, StatementKind::On { event, .. } if event == "error"));
+                if event_name == "error" || !has_error_handler {
+                    report_error(&ctx.origin, &format!("Error in '{}' handler", event_name), &error);
+                    continue;
+                }
+
+                ctx.error = Some(error.kind.to_string());
+                for handler in events {
+                    if let StatementKind::On { event, body, .. } = &handler.kind {
+                        if event == "error" {
+                            if let Err(error) = execute_statements(body, ctx).await {
+                                report_error(&ctx.origin, "Error in 'error' handler", &error);
+                            }
+                        }
+                    }
                 }
+                ctx.error = None;
             }
         }
     }
 }
 
-/// Run the TCP server
-pub async fn run_server(_protocol: &str, port: &str, body: Vec<Statement>) {
+/// Run the TCP server. `shared` holds the top-level declarations visible to every server
+/// and `globals` the values they declared.
+pub async fn run_server(
+    _protocol: &str,
+    port: &str,
+    body: Vec<Statement>,
+    shared: Vec<Statement>,
+    globals: Globals,
+    module: Option<String>,
+    strict: bool,
+) {
     let events = Arc::new(extract_events(&body));
+    let functions = Arc::new(extract_functions(&shared, &body, module.as_deref()));
+
+    // Errors propagate to `on error` handlers when the server has any
+    let strict = strict
+        || events.iter().any(|stmt| matches!(&stmt.kind, StatementKind::On { event, .. } if event == "error"));
 
     let port_str = if port.starts_with(':') {
         format!("127.0.0.1{}", port)
@@ -461,13 +1409,19 @@ pub async fn run_server(_protocol: &str, port: &str, body: Vec<Statement>) {
         println!("Client connected: {}", addr);
 
         let events_clone = Arc::clone(&events);
+        let functions = Arc::clone(&functions);
+        let globals = globals.clone();
+        let module = module.clone();
 
         tokio::spawn(async move {
-            let client_str = addr.port().to_string();
+            let client_port = addr.port();
             let vars: Variables = Arc::new(RwLock::new(HashMap::new()));
 
             // Trigger "connect" events
-            trigger_event(&events_clone, "connect", &mut socket, &addr, None, Some(&client_str), vars.clone()).await;
+            let mut ctx = Context::new(Some(&mut socket), addr.to_string(), None, Some(client_port), vars.clone(), &functions, &globals)
+                .with_module(module.clone())
+                .with_strict(strict);
+            trigger_event(&events_clone, "connect", &mut ctx).await;
 
             let mut buf = vec![0u8; 1024];
 
@@ -475,7 +1429,10 @@ pub async fn run_server(_protocol: &str, port: &str, body: Vec<Statement>) {
                 match socket.read(&mut buf).await {
                     Ok(0) => {
                         println!("Client {} disconnected", addr);
-                        trigger_event(&events_clone, "disconnect", &mut socket, &addr, None, Some(&client_str), vars.clone()).await;
+                        let mut ctx = Context::new(Some(&mut socket), addr.to_string(), None, Some(client_port), vars.clone(), &functions, &globals)
+                            .with_module(module.clone())
+                            .with_strict(strict);
+                        trigger_event(&events_clone, "disconnect", &mut ctx).await;
                         break;
                     }
                     Ok(n) => {
@@ -488,7 +1445,10 @@ pub async fn run_server(_protocol: &str, port: &str, body: Vec<Statement>) {
                         };
                         let msg_trimmed = msg.trim_end_matches(&['\r', '\n'][..]);
                         println!("[{}] RECEIVED: {}", addr, msg_trimmed);
-                        trigger_event(&events_clone, "message", &mut socket, &addr, Some(msg_trimmed), Some(&client_str), vars.clone()).await;
+                        let mut ctx = Context::new(Some(&mut socket), addr.to_string(), Some(msg_trimmed), Some(client_port), vars.clone(), &functions, &globals)
+                            .with_module(module.clone())
+                            .with_strict(strict);
+                        trigger_event(&events_clone, "message", &mut ctx).await;
                     }
                     Err(e) => {
                         eprintln!("[{}] Read error: {}", addr, e);
@@ -498,4 +1458,113 @@ pub async fn run_server(_protocol: &str, port: &str, body: Vec<Statement>) {
             }
         });
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer, parser};
+
+    /// Evaluate the file-scope declarations of `source`, then run the body of
+    /// its `fn handler()` the way a strict `on message` handler runs for
+    /// "bob". Returns `$result` as the handler sees it afterwards, and the globals.
+    async fn run(source: &str) -> Result<(String, Globals), RuntimeError> {
+        let (program, errors) = parser::parse(lexer::lex("test.vi", source).expect("source should lex"));
+        assert!(errors.is_empty(), "source should parse");
+        let Some(body) = program.iter().find_map(|stmt| match &stmt.kind {
+            StatementKind::Function { name, body, .. } if name == "handler" => Some(body),
+            _ => None,
+        }) else {
+            panic!("source should declare `fn handler()`");
+        };
+
+        let globals = Globals::init(&program, true).await?;
+        let functions = extract_functions(&program, &[], None);
+        let vars: Variables = Arc::new(RwLock::new(HashMap::new()));
+        let mut ctx = Context::new(None, "test".to_string(), Some("bob"), None, vars, &functions, &globals)
+            .with_strict(true);
+        execute_statements(body, &mut ctx).await?;
+        let result = ctx.lookup("result").await.unwrap_or(Value::Null).to_string();
+        Ok((result, globals))
+    }
+
+    async fn global(globals: &Globals, name: &str) -> String {
+        globals.get(name).await.unwrap_or(Value::Null).to_string()
+    }
+
+    #[test]
+    fn division_stays_integral_only_when_exact() {
+        let divide = |l, r| eval_arithmetic(Value::Integer(l), &ArithmeticOperator::Divide, Value::Integer(r));
+        assert_eq!(divide(8, 2).unwrap().to_string(), "4");
+        assert_eq!(divide(7, 2).unwrap().to_string(), "3.5");
+        assert!(matches!(divide(i64::MIN, -1), Err(RuntimeError { kind: ErrorKind::Overflow(_), .. })));
+        assert!(matches!(divide(1, 0), Err(RuntimeError { kind: ErrorKind::DivisionByZero, .. })));
+    }
+
+    #[tokio::test]
+    async fn doubled_signs_between_operands_are_arithmetic() {
+        let (result, _) = run(
+            "fn handler() {\n    set n = 5\n    set b = 2\n    set result = [n--1, n++b, 5--2]\n    n++\n    \
+             set result = $result ~ \" \" ~ $n\n}\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, "[6, 7, 7] 6");
+    }
+
+    #[tokio::test]
+    async fn push_and_pop_update_the_list_variable() {
+        let (result, _) = run(
+            "fn handler() {\n    set xs = [1, 2]\n    xs.push(3)\n    set last = xs.pop()\n    xs.pop\n    \
+             set copy = [xs[0]].push(9)\n    set result = [xs, last, copy, [].pop()]\n}\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, "[[1], 3, [1, 9], null]");
+    }
+
+    #[tokio::test]
+    async fn functions_update_connection_variables_in_place() {
+        let (result, _) = run(
+            "fn bump() {\n    count++\n    set copy = 1\n    copy += 1\n    return copy\n}\n\
+             fn handler() {\n    set count = 1\n    set two = bump()\n    bump()\n    set result = [count, two]\n}\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, "[3, 2]");
+    }
+
+    #[tokio::test]
+    async fn loop_variables_do_not_outlive_the_loop() {
+        let (result, globals) = run(
+            "set count = 0\nfn handler() {\n    for count in [7, 8] { }\n    set count = 5\n    count++\n    \
+             set result = $count\n}\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, "6");
+        assert_eq!(global(&globals, "count").await, "6");
+    }
+
+    #[tokio::test]
+    async fn match_bindings_do_not_outlive_the_arm() {
+        let (result, globals) = run(
+            "set user = \"nobody\"\nfn handler() {\n    match $message {\n        user if $user == \"eve\" => { }\n        \
+             user => { set seen = $user }\n    }\n    set user = \"alice\"\n    set result = $seen ~ \" \" ~ $user\n}\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, "bob alice");
+        assert_eq!(global(&globals, "user").await, "alice");
+    }
+
+    #[tokio::test]
+    async fn catch_variables_do_not_outlive_the_catch() {
+        let (result, _) = run(
+            "set problem = \"none\"\nfn handler() {\n    try { set x = 1 / 0 } catch problem { set seen = $problem }\n    \
+             set result = $seen ~ \", \" ~ $problem\n}\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, "division by zero, none");
+    }
+}
@@ -1,23 +1,74 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Where a token or syntax node starts: a file, and a line and column
+/// counting from 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: Arc<str>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// A token and where it starts
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Ident(String),
     String(String),
-    Number(String),
+    /// A string literal containing `$` interpolations
+    Template(Vec<StringPart>),
+    Integer(i64),
+    Float(f64),
     Variable(String),
+    Regex(String),
+    /// Text of a `///` doc comment line
+    DocComment(String),
     LBrace,
     RBrace,
     LParen,
     RParen,
+    LBracket,
+    RBracket,
     Dot,
+    DotDot,
     On,
     Server,
-    TCP,
+    Tcp,
     Log,
     Send,
     Set,
+    Const,
+    Import,
+    As,
     If,
     Else,
+    Fn,
+    Return,
+    While,
+    For,
+    In,
+    Break,
+    Continue,
+    Match,
+    Try,
+    True,
+    False,
+    Null,
+    Catch,
     Equals,
+    FatArrow,
     EqualsEquals,
     NotEquals,
     GreaterThan,
@@ -26,13 +77,118 @@ pub enum Token {
     LessEquals,
     And,
     Or,
+    Question,
+    QuestionQuestion,
+    Pipe,
     Not,
     Plus,
     Minus,
     Star,
     Slash,
     Percent,
+    Tilde,
+    PlusEquals,
+    MinusEquals,
+    StarEquals,
+    SlashEquals,
+    PlusPlus,
+    MinusMinus,
     Colon,
     Comma,
-    EOF
-}
\ No newline at end of file
+    Eof
+}
+
+/// Tokens display as they are written in source, for error messages
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Token::Ident(name) => name,
+            Token::String(s) => return write!(f, "{:?}", s),
+            Token::Template(parts) => {
+                write!(f, "\"")?;
+                for part in parts {
+                    match part {
+                        StringPart::Text(text) => write!(f, "{}", text.escape_debug())?,
+                        StringPart::Interpolation(_) => write!(f, "${{…}}")?,
+                    }
+                }
+                return write!(f, "\"");
+            }
+            Token::Integer(n) => return write!(f, "{}", n),
+            Token::Float(n) => return write!(f, "{:?}", n),
+            Token::Variable(name) => return write!(f, "${}", name),
+            Token::Regex(pattern) => return write!(f, "/{}/", pattern),
+            Token::DocComment(text) => return write!(f, "///{}", text),
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::Dot => ".",
+            Token::DotDot => "..",
+            Token::On => "on",
+            Token::Server => "server",
+            Token::Tcp => "tcp",
+            Token::Log => "log",
+            Token::Send => "send",
+            Token::Set => "set",
+            Token::Const => "const",
+            Token::Import => "import",
+            Token::As => "as",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::Fn => "fn",
+            Token::Return => "return",
+            Token::While => "while",
+            Token::For => "for",
+            Token::In => "in",
+            Token::Break => "break",
+            Token::Continue => "continue",
+            Token::Match => "match",
+            Token::Try => "try",
+            Token::True => "true",
+            Token::False => "false",
+            Token::Null => "null",
+            Token::Catch => "catch",
+            Token::Equals => "=",
+            Token::FatArrow => "=>",
+            Token::EqualsEquals => "==",
+            Token::NotEquals => "!=",
+            Token::GreaterThan => ">",
+            Token::LessThan => "<",
+            Token::GreaterEquals => ">=",
+            Token::LessEquals => "<=",
+            Token::And => "&&",
+            Token::Or => "||",
+            Token::Question => "?",
+            Token::QuestionQuestion => "??",
+            Token::Pipe => "|",
+            Token::Not => "!",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::Percent => "%",
+            Token::Tilde => "~",
+            Token::PlusEquals => "+=",
+            Token::MinusEquals => "-=",
+            Token::StarEquals => "*=",
+            Token::SlashEquals => "/=",
+            Token::PlusPlus => "++",
+            Token::MinusMinus => "--",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Eof => "end of file",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+/// A piece of an interpolated string literal
+#[derive(Debug, Clone, PartialEq)]
+pub enum StringPart {
+    Text(String),
+    /// Tokens of an interpolation, such as `$name.upper()` or `${count + 1}`
+    Interpolation(Vec<SpannedToken>),
+}
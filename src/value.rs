@@ -0,0 +1,156 @@
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A runtime value produced by evaluating an expression
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Null,
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+    Regex(Regex),
+}
+
+impl Value {
+    /// Name reported by `typeof`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "boolean",
+            Value::Null => "null",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Regex(_) => "regex",
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::String(s) => !s.is_empty(),
+            Value::Integer(n) => *n != 0,
+            Value::Float(n) => *n != 0.0,
+            Value::Boolean(b) => *b,
+            Value::Null => false,
+            Value::List(items) => !items.is_empty(),
+            Value::Map(entries) => !entries.is_empty(),
+            Value::Regex(_) => true,
+        }
+    }
+
+    /// Numeric view of the value, used for mixed integer/float arithmetic
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Integer(n) => Some(*n as f64),
+            Value::Float(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Format a value inside a collection, quoting strings so `["1"]` and `[1]` differ
+    fn fmt_nested(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{:?}", s),
+            other => write!(f, "{}", other),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::Integer(n) => write!(f, "{}", n),
+            // Keep a trailing `.0` so floats never print like integers, and
+            // use an exponent once they are too large to print digit by digit
+            Value::Float(n) if n.is_finite() && n.abs() >= 1e16 => write!(f, "{:e}", n),
+            Value::Float(n) if n.is_finite() && n.fract() == 0.0 => write!(f, "{:.1}", n),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Null => write!(f, "null"),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    item.fmt_nested(f)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: ", key)?;
+                    value.fmt_nested(f)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Regex(regex) => write!(f, "/{}/", regex.as_str()),
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Integer(n)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(items: Vec<Value>) -> Self {
+        Value::List(items)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Self {
+        Value::Integer(n as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Value;
+
+    #[test]
+    fn whole_floats_keep_their_point() {
+        assert_eq!(Value::Float(3.0).to_string(), "3.0");
+        assert_eq!(Value::Float(-2.0).to_string(), "-2.0");
+        assert_eq!(Value::Float(1e15).to_string(), "1000000000000000.0");
+        assert_eq!(Value::Float(0.25).to_string(), "0.25");
+    }
+
+    #[test]
+    fn large_floats_use_an_exponent() {
+        assert_eq!(Value::Float(1e300).to_string(), "1e300");
+        assert_eq!(Value::Float(-2.5e20).to_string(), "-2.5e20");
+        assert_eq!(Value::List(vec![Value::Float(1e17)]).to_string(), "[1e17]");
+    }
+}